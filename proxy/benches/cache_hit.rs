@@ -0,0 +1,51 @@
+//! Compares serving a cached 1 MB response with the zero-copy `Bytes` values
+//! against copying the value and the body on every hit, as it was done with `Vec<u8>`.
+//!
+//! Run with `cargo bench -p join-proxy`.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use join_proxy::cache::{cache::Cache, mem_cache::BinaryMemCache};
+use join_proxy::cached_response::CachedResponse;
+
+const BODY_SIZE: usize = 1 << 20;
+const ITERATIONS: u32 = 1000;
+
+async fn hit(cache: &BinaryMemCache, key: &Vec<u8>, copy: bool) -> usize {
+    let value = cache.lock(key).await.unwrap().inner().await.unwrap();
+    let value = if copy { Bytes::from(value.to_vec()) } else { value };
+    let mut response = CachedResponse::deserialize(&value).unwrap();
+    if copy {
+        response.body = Bytes::from(response.body.to_vec());
+    }
+    response.into_http_response().unwrap().into_body().len()
+}
+
+async fn measure(cache: &BinaryMemCache, key: &Vec<u8>, copy: bool) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(hit(cache, key, copy).await, BODY_SIZE);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+#[tokio::main]
+async fn main() {
+    let cache = BinaryMemCache::new(Duration::from_secs(3600));
+    let key = b"key".to_vec();
+    let response = CachedResponse {
+        status: 200,
+        headers: vec![(b"content-type".to_vec(), b"application/json".to_vec())],
+        body: (0..BODY_SIZE).map(|i| i as u8).collect::<Vec<_>>().into(),
+        fetch_info: None,
+    };
+    cache.lock(&key).await.unwrap().set(Some(response.serialize())).await;
+
+    let copying = measure(&cache, &key, true).await;
+    let zero_copy = measure(&cache, &key, false).await;
+    println!("Cache hit with a {} KiB body, {ITERATIONS} iterations:", BODY_SIZE / 1024);
+    println!("  copying values: {copying:?} per hit");
+    println!("  zero-copy:      {zero_copy:?} per hit");
+    println!("  speedup:        {:.1}x", copying.as_secs_f64() / zero_copy.as_secs_f64());
+}
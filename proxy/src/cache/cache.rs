@@ -1,16 +1,81 @@
+use std::time::{Duration, SystemTime};
+
 use async_trait::async_trait;
+use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
 
 use super::lockable_map::MutexGuard;
-use crate::errors::MyResult;
+use crate::errors::{MyResult, UnsupportedError};
+
+/// What is known about a stored entry besides its value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    pub stored_at: SystemTime,
+    pub expires_at: SystemTime,
+    /// Size of the stored value in bytes.
+    pub size: usize,
+    pub upstream_host: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries removed before they expired, to stay within the cache limits.
+    pub evictions: u64,
+}
+
+/// Options of storing a value with `Cache::put`.
+#[derive(Clone, Debug, Default)]
+pub struct PutOptions {
+    /// How long to keep the value, the cache's `cache_timeout` by default.
+    pub ttl: Option<Duration>,
+    pub upstream_host: Option<String>,
+}
 
+/// Methods other than `lock` are for admin tooling and persistence.
+/// Backends that can't support one return `UnsupportedError`.
 #[async_trait]
-pub trait Cache<K, V>: Sync + Send {
-    // type Guard<'a>: MutexGuard<Option<V>> where Self: 'a, V: 'a;
+pub trait Cache<K, V>: Sync + Send
+where
+    K: Sync,
+    V: Send + 'static,
+{
+    /// Locks the entry for `key` only, so that requests with different keys don't wait for each other.
+    async fn lock<'a>(&'a self, key: &K) -> MyResult<Box<dyn MutexGuard<Option<V>> + Send + 'a>> where V: 'a;
+
+    /// The value, without waiting for an upstream request in progress.
+    async fn get(&self, _key: &K) -> MyResult<Option<V>> {
+        Err(UnsupportedError("get").into())
+    }
+
+    async fn put(&self, _key: &K, _value: V, _options: PutOptions) -> MyResult<()> {
+        Err(UnsupportedError("put").into())
+    }
+
+    /// Returns whether the entry existed.
+    async fn remove(&self, _key: &K) -> MyResult<bool> {
+        Err(UnsupportedError("remove").into())
+    }
+
+    async fn keys(&self) -> MyResult<Vec<K>> {
+        Err(UnsupportedError("keys").into())
+    }
+
+    async fn stats(&self) -> MyResult<CacheStats> {
+        Err(UnsupportedError("stats").into())
+    }
 
-    // async fn lock<'a>(&'a mut self, key: &K) -> MyResult<Self::Guard<'a>> where V: 'a;
-    async fn lock<'a>(&'a mut self, key: &K) -> MyResult<Box<dyn MutexGuard<Option<V>> + 'a>> where V: 'a;
+    async fn metadata(&self, _key: &K) -> MyResult<Option<EntryMetadata>> {
+        Err(UnsupportedError("metadata").into())
+    }
 
-    // async fn put(&mut self, key: K, value: V) -> MyResult<()>;
+    /// Persist the cache, for backends that support it.
+    async fn flush(&self) -> MyResult<()> {
+        Ok(())
+    }
 }
 
-pub type BinaryCache = dyn Cache<Vec<u8>, Vec<u8>>;
\ No newline at end of file
+pub type BinaryCache = dyn Cache<Vec<u8>, Bytes>;
@@ -0,0 +1,234 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{info, warn};
+
+use super::{cache::BinaryCache, lockable_map::MutexGuard};
+use crate::{cache::cache::{Cache, CacheStats, EntryMetadata, PutOptions}, config::CompressionConfig, errors::MyResult};
+
+/// Starts compressed values; the zstd frame follows.
+/// Serialized responses start with `0xff` or an ASCII digit, so uncompressed values are never mistaken for it.
+const ZSTD_TAG: u8 = 0xfe;
+
+#[derive(Default)]
+pub struct CompressionStats {
+    pub entries: AtomicU64,
+    pub original_bytes: AtomicU64,
+    pub compressed_bytes: AtomicU64,
+}
+
+/// Compresses values of another cache.
+///
+/// Values shorter than the threshold, or that don't get smaller, are stored as is.
+/// Without `config` nothing is compressed, but values compressed earlier are still read.
+pub struct CompressedCache {
+    inner: Arc<BinaryCache>,
+    config: Option<CompressionConfig>,
+    stats: CompressionStats,
+}
+
+impl CompressedCache {
+    pub fn new(inner: Arc<BinaryCache>, config: Option<CompressionConfig>) -> Self {
+        Self { inner, config, stats: CompressionStats::default() }
+    }
+
+    /// Values compressed since the start.
+    pub fn compression_stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    /// Logs the bytes saved by compression every `interval`, if more values were compressed meanwhile.
+    pub fn spawn_stats_logger(self: &Arc<Self>, interval: Duration) {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            let mut logged_entries = 0;
+            loop {
+                interval.tick().await;
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                let entries = cache.stats.entries.load(Ordering::Relaxed);
+                if entries != logged_entries {
+                    cache.log_stats();
+                    logged_entries = entries;
+                }
+            }
+        });
+    }
+
+    fn log_stats(&self) {
+        let original = self.stats.original_bytes.load(Ordering::Relaxed);
+        let compressed = self.stats.compressed_bytes.load(Ordering::Relaxed);
+        info!(
+            "Compressed {} cache entries, saved {} bytes ({} -> {}).",
+            self.stats.entries.load(Ordering::Relaxed), original - compressed, original, compressed,
+        );
+    }
+}
+
+fn compress(value: Bytes, config: &Option<CompressionConfig>, stats: &CompressionStats) -> Bytes {
+    let Some(config) = config else {
+        return value;
+    };
+    if value.len() < config.min_size {
+        return value;
+    }
+    match zstd::bulk::compress(&value, config.level) {
+        Ok(compressed) if compressed.len() + 1 < value.len() => {
+            stats.entries.fetch_add(1, Ordering::Relaxed);
+            stats.original_bytes.fetch_add(value.len() as u64, Ordering::Relaxed);
+            stats.compressed_bytes.fetch_add(compressed.len() as u64 + 1, Ordering::Relaxed);
+            [&[ZSTD_TAG], compressed.as_slice()].concat().into()
+        }
+        Ok(_) => value,
+        Err(e) => {
+            warn!("Cannot compress cache value: {e}");
+            value
+        }
+    }
+}
+
+fn decompress(value: Bytes) -> Option<Bytes> {
+    match value.split_first() {
+        Some((&ZSTD_TAG, compressed)) => match zstd::stream::decode_all(compressed) {
+            Ok(value) => Some(value.into()),
+            Err(e) => {
+                warn!("Cannot decompress cache value, ignoring it: {e}");
+                None
+            }
+        },
+        _ => Some(value),
+    }
+}
+
+#[async_trait]
+impl Cache<Vec<u8>, Bytes> for CompressedCache {
+    async fn lock<'a>(&'a self, key: &Vec<u8>) -> MyResult<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>
+        where Bytes: 'a
+    {
+        let inner = self.inner.lock(key).await?;
+        let value = inner.inner().await.and_then(decompress);
+        Ok(Box::new(CompressedGuard { inner: std::sync::Mutex::new(inner), value, cache: self }))
+    }
+
+    async fn get(&self, key: &Vec<u8>) -> MyResult<Option<Bytes>> {
+        Ok(self.inner.get(key).await?.and_then(decompress))
+    }
+
+    async fn put(&self, key: &Vec<u8>, value: Bytes, options: PutOptions) -> MyResult<()> {
+        self.inner.put(key, compress(value, &self.config, &self.stats), options).await
+    }
+
+    async fn remove(&self, key: &Vec<u8>) -> MyResult<bool> {
+        self.inner.remove(key).await
+    }
+
+    async fn keys(&self) -> MyResult<Vec<Vec<u8>>> {
+        self.inner.keys().await
+    }
+
+    /// `bytes` are counted after compression.
+    async fn stats(&self) -> MyResult<CacheStats> {
+        self.inner.stats().await
+    }
+
+    async fn metadata(&self, key: &Vec<u8>) -> MyResult<Option<EntryMetadata>> {
+        self.inner.metadata(key).await
+    }
+
+    async fn flush(&self) -> MyResult<()> {
+        self.inner.flush().await
+    }
+}
+
+/// The inner guard is only `Send`, see `TieredGuard`.
+pub struct CompressedGuard<'a> {
+    inner: std::sync::Mutex<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>,
+    value: Option<Bytes>,
+    cache: &'a CompressedCache,
+}
+
+impl<'a> Deref for CompressedGuard<'a> {
+    type Target = Option<Bytes>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[async_trait]
+impl<'a> MutexGuard<Option<Bytes>> for CompressedGuard<'a> {
+    async fn set_with_ttl(&mut self, value: Option<Bytes>, ttl: Option<Duration>) {
+        self.set_with_options(value, PutOptions { ttl, upstream_host: None }).await;
+    }
+
+    async fn set_with_options(&mut self, value: Option<Bytes>, options: PutOptions) {
+        let stored = value.clone().map(|value| compress(value, &self.cache.config, &self.cache.stats));
+        self.inner.get_mut().unwrap().set_with_options(stored, options).await;
+        self.value = value;
+    }
+
+    async fn inner(&self) -> Option<Bytes> {
+        self.value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::cache::{cache::Cache, mem_cache::BinaryMemCache};
+    use crate::config::CompressionConfig;
+
+    use super::{CompressedCache, ZSTD_TAG};
+
+    fn caches(config: Option<CompressionConfig>) -> (Arc<BinaryMemCache>, CompressedCache) {
+        let inner = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let compressed = CompressedCache::new(inner.clone(), config);
+        (inner, compressed)
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let (inner, cache) = caches(Some(CompressionConfig { level: 3, min_size: 100, stats_interval: Duration::from_secs(300) }));
+        let value = Bytes::from(b"{\"choices\": []}".repeat(100));
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(value.clone())).await;
+
+        let stored = inner.lock(&b"key".to_vec()).await.unwrap().inner().await.unwrap();
+        assert_eq!(stored[0], ZSTD_TAG);
+        assert!(stored.len() < value.len() / 5);
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(value.clone()));
+        let stats = cache.compression_stats();
+        assert_eq!(stats.entries.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.original_bytes.load(Ordering::Relaxed), value.len() as u64);
+        assert_eq!(stats.compressed_bytes.load(Ordering::Relaxed), stored.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_small_value_is_not_compressed() {
+        let (inner, cache) = caches(Some(CompressionConfig { level: 3, min_size: 100, stats_interval: Duration::from_secs(300) }));
+        let value = Bytes::from_static(b"200\n\nsmall");
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(value.clone())).await;
+        assert_eq!(inner.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(value));
+        assert_eq!(cache.stats.entries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_value_is_read_without_compression() {
+        let (inner, cache) = caches(Some(CompressionConfig { level: 3, min_size: 0, stats_interval: Duration::from_secs(300) }));
+        let value = Bytes::from(b"x".repeat(1000));
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(value.clone())).await;
+
+        let uncompressing = CompressedCache::new(inner, None);
+        assert_eq!(uncompressing.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(value));
+    }
+}
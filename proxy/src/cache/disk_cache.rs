@@ -0,0 +1,219 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{debug, warn};
+
+use super::lockable_map::{AbstractLockableMap, LockableHashMap, MutexGuard};
+use crate::{cache::cache::Cache, config::DiskCacheConfig, errors::{MyCorruptedDBError, MyResult}};
+
+/// Cache stored in an embedded on-disk database, for responses that don't fit in RAM.
+///
+/// Every value is prefixed with its expiration time (milliseconds since UNIX epoch, big endian).
+/// The `expirations` tree maps `expiration time ++ key` to nothing, to find expired entries quickly.
+pub struct DiskCache {
+    data: sled::Tree,
+    expirations: sled::Tree,
+    locks: LockableHashMap<Vec<u8>, ()>,
+    keep_duration: Duration,
+}
+
+impl DiskCache {
+    pub fn new(config: &DiskCacheConfig, keep_duration: Duration) -> MyResult<Self> {
+        Self::from_db(sled::open(&config.path)?, keep_duration)
+    }
+
+    fn from_db(db: sled::Db, keep_duration: Duration) -> MyResult<Self> {
+        Ok(Self {
+            data: db.open_tree("data")?,
+            expirations: db.open_tree("expirations")?,
+            locks: LockableHashMap::new(),
+            keep_duration,
+        })
+    }
+
+    /// Removes expired entries every `interval`, until the cache is dropped.
+    pub fn spawn_reaper(self: &Arc<Self>, interval: Duration) {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                if let Err(e) = cache.remove_expired().await {
+                    warn!("Cannot remove expired entries from disk: {e}");
+                }
+            }
+        });
+    }
+
+    async fn remove_expired(&self) -> MyResult<()> {
+        let now = now_millis().to_be_bytes();
+        let mut removed = 0;
+        for item in self.expirations.range(..now.as_slice()) {
+            let (expiration_key, _) = item?;
+            let key = expiration_key[8..].to_vec();
+            // An entry being read or fetched is removed next time.
+            if !self.locks.remove(&key).await {
+                continue;
+            }
+            // The entry may have been put again since then.
+            if let Some(entry) = self.data.get(&key)? {
+                if entry_expires_at(&entry)? <= u64::from_be_bytes(now) {
+                    self.data.compare_and_swap(&key, Some(entry), None as Option<&[u8]>)?.ok();
+                    removed += 1;
+                }
+            }
+            self.expirations.remove(expiration_key)?;
+        }
+        if removed != 0 {
+            debug!("Removed {removed} expired entries from disk.");
+        }
+        Ok(())
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn entry_expires_at(entry: &[u8]) -> MyResult<u64> {
+    let bytes = entry.get(..8).ok_or_else(MyCorruptedDBError::default)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[async_trait]
+impl Cache<Vec<u8>, Bytes> for DiskCache {
+    async fn lock<'a>(&'a self, key: &Vec<u8>) -> MyResult<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>
+        where Bytes: 'a
+    {
+        // Expired entries are removed by the reaper task, not here.
+        let lock = self.locks.lock(key).await;
+        let value = match self.data.get(key)? {
+            Some(entry) if entry_expires_at(&entry)? > now_millis() => Some(Bytes::copy_from_slice(&entry[8..])),
+            _ => None,
+        };
+        Ok(Box::new(DiskGuard {
+            lock,
+            locks: &self.locks,
+            data: &self.data,
+            expirations: &self.expirations,
+            key: key.clone(),
+            value,
+            keep_duration: self.keep_duration,
+        }))
+    }
+
+    async fn flush(&self) -> MyResult<()> {
+        self.data.flush_async().await?;
+        self.expirations.flush_async().await?;
+        Ok(())
+    }
+}
+
+pub struct DiskGuard<'a> {
+    lock: tokio::sync::OwnedMutexGuard<Option<()>>,
+    locks: &'a LockableHashMap<Vec<u8>, ()>,
+    data: &'a sled::Tree,
+    expirations: &'a sled::Tree,
+    key: Vec<u8>,
+    value: Option<Bytes>,
+    keep_duration: Duration,
+}
+
+impl<'a> DiskGuard<'a> {
+    fn store(&self, value: &Option<Bytes>, ttl: Duration) -> MyResult<()> {
+        if let Some(value) = value {
+            let expires_at = (now_millis() + ttl.as_millis() as u64).to_be_bytes();
+            self.data.insert(&self.key, [expires_at.as_slice(), value.as_ref()].concat())?;
+            self.expirations.insert([expires_at.as_slice(), &self.key].concat(), &[])?;
+        } else {
+            self.data.remove(&self.key)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for DiskGuard<'a> {
+    fn drop(&mut self) {
+        self.locks.remove_unused(&self.key, &self.lock);
+    }
+}
+
+impl<'a> Deref for DiskGuard<'a> {
+    type Target = Option<Bytes>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[async_trait]
+impl<'a> MutexGuard<Option<Bytes>> for DiskGuard<'a> {
+    async fn set_with_ttl(&mut self, value: Option<Bytes>, ttl: Option<Duration>) {
+        if let Err(e) = self.store(&value, ttl.unwrap_or(self.keep_duration)) {
+            warn!("Cannot store value on disk: {e}");
+        }
+        self.value = value;
+    }
+
+    async fn inner(&self) -> Option<Bytes> {
+        self.value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::cache::cache::Cache;
+
+    use super::DiskCache;
+
+    fn temporary_cache(keep_duration: Duration) -> DiskCache {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        DiskCache::from_db(db, keep_duration).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let cache = temporary_cache(Duration::from_secs(60));
+        let key = b"key".to_vec();
+        {
+            let mut guard = cache.lock(&key).await.unwrap();
+            assert!(guard.inner().await.is_none());
+            guard.set(Some(Bytes::from_static(b"value"))).await;
+        }
+        assert_eq!(cache.lock(&key).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let cache = Arc::new(temporary_cache(Duration::from_millis(100)));
+        cache.spawn_reaper(Duration::from_millis(10));
+        let key = b"key".to_vec();
+        cache.lock(&key).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cache.lock(&key).await.unwrap().inner().await.is_none());
+        assert!(cache.data.is_empty());
+        assert!(cache.expirations.is_empty());
+        assert!(cache.locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ttl_per_entry() {
+        let cache = temporary_cache(Duration::from_secs(60));
+        cache.lock(&b"short".to_vec()).await.unwrap().set_with_ttl(Some(Bytes::from_static(b"value")), Some(Duration::from_millis(100))).await;
+        cache.lock(&b"long".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(cache.lock(&b"short".to_vec()).await.unwrap().inner().await.is_none());
+        assert_eq!(cache.lock(&b"long".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+    }
+}
@@ -0,0 +1,259 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use super::{cache::BinaryCache, lockable_map::MutexGuard};
+use crate::{cache::cache::{Cache, CacheStats, EntryMetadata, PutOptions}, config::EncryptionConfig, errors::MyResult};
+
+/// Starts encrypted values, followed by the key ID, the nonce, and the ciphertext with the tag.
+const ENCRYPTED_TAG: u8 = 0xfd;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+pub struct EncryptionKey {
+    /// A hash of the key, to find the key an entry was encrypted with.
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let id = Sha256::digest(key)[..KEY_ID_LEN].try_into().unwrap();
+        Self { id, cipher: Aes256Gcm::new(key.into()) }
+    }
+
+    /// Reads a base64 encoded 256-bit key, such as generated by `openssl rand -base64 32`.
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let key = base64::engine::general_purpose::STANDARD.decode(text.trim())?;
+        let key: [u8; 32] = key.try_into()
+            .map_err(|_| anyhow::anyhow!("Encryption key in {path} must be 32 bytes long"))?;
+        Ok(Self::new(&key))
+    }
+}
+
+pub struct EncryptionKeys {
+    /// New entries are encrypted with this key.
+    current: EncryptionKey,
+    /// Entries encrypted with these keys are still read.
+    previous: Vec<EncryptionKey>,
+}
+
+impl EncryptionKeys {
+    pub fn load(config: &EncryptionConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            current: EncryptionKey::from_file(&config.key_file)?,
+            previous: config.previous_key_files.iter()
+                .map(|path| EncryptionKey::from_file(path))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    fn find(&self, id: &[u8]) -> Option<&EncryptionKey> {
+        std::iter::once(&self.current).chain(self.previous.iter()).find(|key| key.id == id)
+    }
+}
+
+/// Encrypts values of another cache with AES-256-GCM.
+///
+/// The cache key is authenticated together with the value, so that an entry can't be moved to another key.
+/// Values that aren't encrypted with one of the keys are treated as missing;
+/// without keys, only unencrypted values are read.
+pub struct EncryptedCache {
+    inner: Arc<BinaryCache>,
+    keys: Option<EncryptionKeys>,
+}
+
+impl EncryptedCache {
+    pub fn new(inner: Arc<BinaryCache>, keys: Option<EncryptionKeys>) -> Self {
+        Self { inner, keys }
+    }
+
+    fn encrypt(&self, key: &[u8], value: Bytes) -> Option<Bytes> {
+        let Some(keys) = &self.keys else {
+            return Some(value);
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        match keys.current.cipher.encrypt(&nonce, Payload { msg: &value, aad: key }) {
+            Ok(ciphertext) => Some([&[ENCRYPTED_TAG], keys.current.id.as_slice(), nonce.as_slice(), &ciphertext].concat().into()),
+            Err(e) => {
+                warn!("Cannot encrypt cache value: {e}");
+                None
+            }
+        }
+    }
+
+    fn decrypt(&self, key: &[u8], value: Bytes) -> Option<Bytes> {
+        match (value.split_first(), &self.keys) {
+            (Some((&ENCRYPTED_TAG, data)), Some(keys)) if data.len() >= KEY_ID_LEN + NONCE_LEN => {
+                let (id, data) = data.split_at(KEY_ID_LEN);
+                let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+                let Some(encryption_key) = keys.find(id) else {
+                    warn!("Cache value is encrypted with an unknown key, ignoring it.");
+                    return None;
+                };
+                match encryption_key.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: key }) {
+                    Ok(value) => Some(value.into()),
+                    Err(_) => {
+                        warn!("Cannot decrypt cache value, ignoring it.");
+                        None
+                    }
+                }
+            }
+            (Some((&ENCRYPTED_TAG, _)), _) => {
+                warn!("Cannot decrypt cache value, ignoring it.");
+                None
+            }
+            (_, Some(_)) => None, // not written by us, as we encrypt everything
+            (_, None) => Some(value),
+        }
+    }
+}
+
+#[async_trait]
+impl Cache<Vec<u8>, Bytes> for EncryptedCache {
+    async fn lock<'a>(&'a self, key: &Vec<u8>) -> MyResult<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>
+        where Bytes: 'a
+    {
+        let inner = self.inner.lock(key).await?;
+        let value = inner.inner().await.and_then(|value| self.decrypt(key, value));
+        Ok(Box::new(EncryptedGuard { inner: std::sync::Mutex::new(inner), key: key.clone(), value, cache: self }))
+    }
+
+    async fn get(&self, key: &Vec<u8>) -> MyResult<Option<Bytes>> {
+        Ok(self.inner.get(key).await?.and_then(|value| self.decrypt(key, value)))
+    }
+
+    /// A value that can't be encrypted is not stored.
+    async fn put(&self, key: &Vec<u8>, value: Bytes, options: PutOptions) -> MyResult<()> {
+        match self.encrypt(key, value) {
+            Some(value) => self.inner.put(key, value, options).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn remove(&self, key: &Vec<u8>) -> MyResult<bool> {
+        self.inner.remove(key).await
+    }
+
+    async fn keys(&self) -> MyResult<Vec<Vec<u8>>> {
+        self.inner.keys().await
+    }
+
+    async fn stats(&self) -> MyResult<CacheStats> {
+        self.inner.stats().await
+    }
+
+    async fn metadata(&self, key: &Vec<u8>) -> MyResult<Option<EntryMetadata>> {
+        self.inner.metadata(key).await
+    }
+
+    async fn flush(&self) -> MyResult<()> {
+        self.inner.flush().await
+    }
+}
+
+/// The inner guard is only `Send`, see `TieredGuard`.
+pub struct EncryptedGuard<'a> {
+    inner: std::sync::Mutex<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>,
+    key: Vec<u8>,
+    value: Option<Bytes>,
+    cache: &'a EncryptedCache,
+}
+
+impl<'a> Deref for EncryptedGuard<'a> {
+    type Target = Option<Bytes>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[async_trait]
+impl<'a> MutexGuard<Option<Bytes>> for EncryptedGuard<'a> {
+    async fn set_with_ttl(&mut self, value: Option<Bytes>, ttl: Option<Duration>) {
+        self.set_with_options(value, PutOptions { ttl, upstream_host: None }).await;
+    }
+
+    async fn set_with_options(&mut self, value: Option<Bytes>, options: PutOptions) {
+        let stored = value.clone().and_then(|value| self.cache.encrypt(&self.key, value));
+        self.inner.get_mut().unwrap().set_with_options(stored, options).await;
+        self.value = value;
+    }
+
+    async fn inner(&self) -> Option<Bytes> {
+        self.value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::cache::{cache::Cache, mem_cache::BinaryMemCache};
+
+    use super::{EncryptedCache, EncryptionKey, EncryptionKeys};
+
+    fn keys(current: u8, previous: &[u8]) -> Option<EncryptionKeys> {
+        Some(EncryptionKeys {
+            current: EncryptionKey::new(&[current; 32]),
+            previous: previous.iter().map(|&k| EncryptionKey::new(&[k; 32])).collect(),
+        })
+    }
+
+    async fn get(cache: &EncryptedCache, key: &[u8]) -> Option<Bytes> {
+        cache.lock(&key.to_vec()).await.unwrap().inner().await
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        let inner = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let cache = EncryptedCache::new(inner.clone(), keys(1, &[]));
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"secret prompt"))).await;
+
+        let stored = inner.lock(&b"key".to_vec()).await.unwrap().inner().await.unwrap();
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        assert_eq!(get(&cache, b"key").await, Some(Bytes::from_static(b"secret prompt")));
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let inner = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let old = EncryptedCache::new(inner.clone(), keys(1, &[]));
+        old.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+
+        let rotated = EncryptedCache::new(inner.clone(), keys(2, &[1]));
+        assert_eq!(get(&rotated, b"key").await, Some(Bytes::from_static(b"value")));
+        let unrelated = EncryptedCache::new(inner, keys(3, &[]));
+        assert_eq!(get(&unrelated, b"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_value_moved_to_another_key() {
+        let inner = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let cache = EncryptedCache::new(inner.clone(), keys(1, &[]));
+        cache.lock(&b"key1".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        let stored = inner.lock(&b"key1".to_vec()).await.unwrap().inner().await;
+        inner.lock(&b"key2".to_vec()).await.unwrap().set(stored).await;
+        assert_eq!(get(&cache, b"key2").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_is_ignored() {
+        let inner = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        inner.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"200\n\nforged"))).await;
+        let cache = EncryptedCache::new(inner, keys(1, &[]));
+        assert_eq!(get(&cache, b"key").await, None);
+    }
+}
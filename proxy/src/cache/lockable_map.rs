@@ -1,19 +1,32 @@
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
+use super::cache::PutOptions;
+
 #[async_trait]
 pub trait MutexGuard<T>: Deref<Target = T> /*+ DerefMut<Target = T>*/ {
-    async fn set(&mut self, value: T);
-    async fn inner(&self) -> T where T: Sized + Clone + std::marker::Sync; // TODO: `Clone` is bad.
+    async fn set(&mut self, value: T) where T: Send + 'static, Self: Send {
+        self.set_with_ttl(value, None).await;
+    }
+    /// `ttl` overrides the cache's `cache_timeout` for this entry.
+    async fn set_with_ttl(&mut self, value: T, ttl: Option<Duration>);
+    /// Like `Cache::put`. Backends that don't keep metadata only use the TTL.
+    async fn set_with_options(&mut self, value: T, options: PutOptions) where T: Send + 'static, Self: Send {
+        self.set_with_ttl(value, options.ttl).await;
+    }
+    async fn inner(&self) -> T where T: Sized + Clone + std::marker::Sync; // Cache values are `Bytes`, cheap to clone.
 }
 
 #[async_trait]
-impl<T> MutexGuard<T> for tokio::sync::MutexGuard<'_, T>
+impl<T> MutexGuard<T> for tokio::sync::OwnedMutexGuard<T>
     where T: std::marker::Send
 {
-    async fn set(&mut self, value: T) {
+    /// A plain map has no expiration.
+    async fn set_with_ttl(&mut self, value: T, _ttl: Option<Duration>) {
         *self.deref_mut() = value;
     }
 
@@ -23,21 +36,64 @@ impl<T> MutexGuard<T> for tokio::sync::MutexGuard<'_, T>
     }
 }
 
+/// A map that can be locked per key. Locking one key doesn't block other keys.
 pub trait AbstractLockableMap<K, V> {
-    type Guard<'a>: MutexGuard<Option<V>> where Self: 'a;
+    type Guard: MutexGuard<Option<V>>;
 
-    async fn lock(&mut self, key: &K) -> Self::Guard<'_>;
+    async fn lock(&self, key: &K) -> Self::Guard;
 
-    async fn remove(&mut self, key: &K);
+    /// Removes the entry unless it is locked or waited for. Returns `false` only if it is locked or waited for,
+    /// an absent entry counts as removed, so that callers can go on to drop the data kept for the key elsewhere.
+    async fn remove(&self, key: &K) -> bool;
 }
 
+type Slot<V> = Arc<tokio::sync::Mutex<Option<V>>>;
+
 pub struct LockableHashMap<K, V> {
-    map: HashMap<K, tokio::sync::Mutex<Option<V>>>,
+    // The outer lock is held only for a moment to find the entry.
+    map: std::sync::Mutex<HashMap<K, Slot<V>>>,
 }
 
 impl<K, V> LockableHashMap<K, V> {
     pub fn new() -> Self {
-        Self { map: HashMap::new() }
+        Self { map: std::sync::Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<K, V> LockableHashMap<K, V>
+where
+    K: std::hash::Hash + Eq,
+{
+    pub fn insert(&self, key: K, value: V) {
+        self.map.lock().unwrap().insert(key, Arc::new(tokio::sync::Mutex::new(Some(value))));
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.map.lock().unwrap().is_empty()
+    }
+
+    /// Removes the entry locked by `guard` if it is empty and no one else waits for it,
+    /// so that keys that are never stored don't accumulate.
+    pub fn remove_unused(&self, key: &K, guard: &tokio::sync::OwnedMutexGuard<Option<V>>) {
+        if guard.is_some() {
+            return;
+        }
+        let mut map = self.map.lock().unwrap();
+        match map.get(key) {
+            // The map and `guard` hold the only references to the slot.
+            Some(slot) if Arc::ptr_eq(slot, tokio::sync::OwnedMutexGuard::mutex(guard)) && Arc::strong_count(slot) == 2 => {
+                map.remove(key);
+            }
+            _ => {}
+        }
+    }
+
+    /// The value, unless the entry is currently locked.
+    pub fn try_get(&self, key: &K) -> Option<V> where V: Clone {
+        let slot = self.map.lock().unwrap().get(key)?.clone();
+        let value = slot.try_lock().ok()?.clone();
+        value
     }
 }
 
@@ -47,16 +103,25 @@ where
     K: std::hash::Hash + Eq + Clone, // TODO: Is `Clone` needed?
     V: std::marker::Send, // TODO: It is an over-specification.
 {
-    type Guard<'a> = tokio::sync::MutexGuard<'a, Option<V>> where K: 'a, V: 'a;
+    type Guard = tokio::sync::OwnedMutexGuard<Option<V>>;
 
-    async fn lock(&mut self, key: &K) -> tokio::sync::MutexGuard<Option<V>> {
-        self.map.entry(key.clone())
-            .or_insert_with(|| tokio::sync::Mutex::new(None))
-            .lock()
-            .await
+    async fn lock(&self, key: &K) -> tokio::sync::OwnedMutexGuard<Option<V>> {
+        let slot = self.map.lock().unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(None)))
+            .clone();
+        slot.lock_owned().await
     }
 
-    async fn remove(&mut self, key: &K) {
-        self.map.remove(key);
+    async fn remove(&self, key: &K) -> bool {
+        let mut map = self.map.lock().unwrap();
+        match map.get(key) {
+            // Guards and waiters hold their own references to the slot.
+            Some(slot) if Arc::strong_count(slot) > 1 => false,
+            _ => {
+                map.remove(key);
+                true
+            }
+        }
     }
 }
@@ -1,55 +1,770 @@
 use std::hash::Hash;
+use std::ops::{Bound, Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use std::collections::BTreeMap;
+use std::collections::{btree_map, BTreeMap, HashMap};
 use super::lockable_map::{AbstractLockableMap, LockableHashMap, MutexGuard};
 
 use async_trait::async_trait;
-use tokio::sync::Mutex;
+use bytes::Bytes;
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
-use crate::{cache::cache::Cache, errors::MyResult};
+use crate::{cache::cache::{Cache, CacheStats, EntryMetadata, PutOptions}, config::EvictionPolicy, errors::MyResult};
+
+/// Approximate memory taken by a key or a value, to enforce `max_bytes`.
+pub trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for Vec<u8> {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for Bytes {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Starts a snapshot file, to tell it from the format without metadata.
+const SNAPSHOT_MAGIC: &[u8] = b"JPSNAP02";
 
 pub struct MemCache<K, V> {
     data: LockableHashMap<K, V>, // TODO: Use `dashmap` crate instead?
-    put_times: Mutex<BTreeMap<SystemTime, Vec<K>>>,
+    index: Mutex<Index<K>>,
     keep_duration: Duration,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    evictions: AtomicU64, // because of `max_entries` or `max_bytes`
+    hits: AtomicU64,
+    misses: AtomicU64,
+    snapshot_file: Option<PathBuf>,
+}
+
+struct EntryInfo {
+    metadata: EntryMetadata,
+    size: usize, // of both key and value
+    hits: u64,
+    last_access: u64,
+}
+
+/// Bookkeeping of stored entries: when they expire and how they are used (for eviction).
+struct Index<K> {
+    by_expiry: BTreeMap<SystemTime, Vec<K>>,
+    entries: HashMap<K, EntryInfo>,
+    /// Entries in eviction order, the first one is evicted first.
+    eviction_order: BTreeMap<(u64, u64), K>,
+    policy: EvictionPolicy,
+    bytes: usize,
+    tick: u64,
+}
+
+impl<K> Index<K>
+where
+    K: Clone + Hash + std::cmp::Eq,
+{
+    fn new(policy: EvictionPolicy) -> Self {
+        Self {
+            by_expiry: BTreeMap::new(),
+            entries: HashMap::new(),
+            eviction_order: BTreeMap::new(),
+            policy,
+            bytes: 0,
+            tick: 0,
+        }
+    }
+
+    fn rank(&self, info: &EntryInfo) -> (u64, u64) {
+        match self.policy {
+            EvictionPolicy::Lru => (info.last_access, 0),
+            EvictionPolicy::Lfu => (info.hits, info.last_access),
+        }
+    }
+
+    fn insert(&mut self, key: K, metadata: EntryMetadata, size: usize) {
+        self.remove(&key);
+        self.tick += 1;
+        self.by_expiry.entry(metadata.expires_at).or_default().push(key.clone());
+        let info = EntryInfo { metadata, size, hits: 0, last_access: self.tick };
+        self.eviction_order.insert(self.rank(&info), key.clone());
+        self.bytes += size;
+        self.entries.insert(key, info);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(info) = self.entries.remove(key) {
+            if let btree_map::Entry::Occupied(mut entry) = self.by_expiry.entry(info.metadata.expires_at) {
+                entry.get_mut().retain(|k| k != key);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            self.eviction_order.remove(&self.rank(&info));
+            self.bytes -= info.size;
+        }
+    }
+
+    /// Records a cache hit.
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(mut info) = self.entries.remove(key) {
+            self.eviction_order.remove(&self.rank(&info));
+            info.hits += 1;
+            info.last_access = tick;
+            self.eviction_order.insert(self.rank(&info), key.clone());
+            self.entries.insert(key.clone(), info);
+        }
+    }
 }
 
-impl<K, V> MemCache<K, V> {
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry<K, V> {
+    metadata: EntryMetadata,
+    key: K,
+    value: V,
+}
+
+/// Snapshot entry before metadata was stored.
+#[derive(Serialize, Deserialize)]
+struct LegacySnapshotEntry<K, V> {
+    put_time: SystemTime,
+    key: K,
+    value: V,
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq,
+{
     pub fn new(keep_duration: Duration) -> Self {
         Self {
             data: LockableHashMap::new(),
-            put_times: Mutex::new(BTreeMap::new()),
+            index: Mutex::new(Index::new(EvictionPolicy::Lru)),
             keep_duration,
+            max_entries: None,
+            max_bytes: None,
+            evictions: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            snapshot_file: None,
         }
     }
+
+    /// Limits the number of entries and their total size, evicting entries by `policy`.
+    pub fn with_limits(mut self, max_entries: Option<usize>, max_bytes: Option<usize>, policy: EvictionPolicy) -> Self {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        let index = self.index.get_mut();
+        index.policy = policy;
+        let entries = std::mem::take(&mut index.entries);
+        index.eviction_order.clear();
+        for (key, info) in entries {
+            index.eviction_order.insert(index.rank(&info), key.clone());
+            index.entries.insert(key, info);
+        }
+        self
+    }
+
+    fn over_limits(&self, index: &Index<K>) -> bool {
+        self.max_entries.is_some_and(|max| index.entries.len() > max) ||
+            self.max_bytes.is_some_and(|max| index.bytes > max)
+    }
+
+    fn count_access(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + ByteSize + DeserializeOwned,
+    V: ByteSize + DeserializeOwned,
+{
+    /// Creates a cache that is loaded from and saved to `snapshot_file`.
+    /// Expired entries are discarded on loading.
+    pub fn with_snapshot(keep_duration: Duration, snapshot_file: PathBuf) -> MyResult<Self> {
+        let mut cache = Self::new(keep_duration);
+        cache.load_snapshot(&snapshot_file)?;
+        cache.snapshot_file = Some(snapshot_file);
+        Ok(cache)
+    }
+
+    fn load_snapshot(&mut self, snapshot_file: &Path) -> MyResult<()> {
+        if !snapshot_file.exists() {
+            return Ok(());
+        }
+        let data = std::fs::read(snapshot_file)?;
+        let entries: Vec<SnapshotEntry<K, V>> = match data.strip_prefix(SNAPSHOT_MAGIC) {
+            Some(data) => bincode::deserialize(data)?,
+            None => {
+                let legacy: Vec<LegacySnapshotEntry<K, V>> = bincode::deserialize(&data)?;
+                legacy.into_iter().map(|entry| SnapshotEntry {
+                    metadata: EntryMetadata {
+                        stored_at: entry.put_time,
+                        expires_at: entry.put_time + self.keep_duration,
+                        size: entry.value.byte_size(),
+                        upstream_host: None,
+                    },
+                    key: entry.key,
+                    value: entry.value,
+                }).collect()
+            }
+        };
+        let now = SystemTime::now();
+        let index = self.index.get_mut();
+        let mut loaded = 0;
+        for entry in entries.into_iter().filter(|entry| entry.metadata.expires_at > now) {
+            index.insert(entry.key.clone(), entry.metadata, entry.key.byte_size() + entry.value.byte_size());
+            self.data.insert(entry.key, entry.value);
+            loaded += 1;
+        }
+        info!("Loaded {loaded} cache entries from {}.", snapshot_file.display());
+        Ok(())
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + serde::Serialize,
+    V: Clone + serde::Serialize,
+{
+    async fn save_snapshot(&self, snapshot_file: &Path) -> MyResult<()> {
+        let metadata = self.index.lock().await.entries.iter()
+            .map(|(key, info)| (key.clone(), info.metadata.clone()))
+            .collect::<Vec<_>>();
+        let entries = metadata.into_iter()
+            .filter_map(|(key, metadata)| {
+                // Entries locked for an upstream request are skipped.
+                self.data.try_get(&key).map(|value| SnapshotEntry { metadata, key, value })
+            })
+            .collect::<Vec<_>>();
+        let bytes = [SNAPSHOT_MAGIC, &bincode::serialize(&entries)?].concat();
+
+        // Write to a temporary file first not to corrupt the snapshot on a crash.
+        let mut tmp_file = snapshot_file.as_os_str().to_owned();
+        tmp_file.push(".tmp");
+        tokio::fs::write(&tmp_file, bytes).await?;
+        tokio::fs::rename(&tmp_file, snapshot_file).await?;
+        info!("Saved {} cache entries to {}.", entries.len(), snapshot_file.display());
+        Ok(())
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + std::marker::Sync + std::marker::Send + 'static,
+    V: std::marker::Sync + std::marker::Send + 'static,
+{
+    /// Removes expired entries every `interval`, until the cache is dropped.
+    pub fn spawn_reaper(self: &Arc<Self>, interval: Duration) {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                cache.remove_expired().await;
+            }
+        });
+    }
+
+    async fn remove_expired(&self) {
+        let now = SystemTime::now();
+
+        let mut index = self.index.lock().await;
+        let expired = index.by_expiry.range(..now)
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect::<Vec<_>>();
+        let mut removed = 0;
+        for key in expired {
+            // An entry being read or fetched is removed next time.
+            if self.data.remove(&key).await {
+                index.remove(&key);
+                removed += 1;
+            }
+        }
+        if removed != 0 {
+            debug!("Removed {removed} expired cache entries.");
+        }
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + std::marker::Sync + std::marker::Send,
+    V: std::marker::Send,
+{
+    /// Evicts the entries over the limits, such as those loaded from a snapshot saved with higher limits.
+    pub async fn trim(&self) {
+        let mut index = self.index.lock().await;
+        self.evict(&mut index).await;
+    }
+
+    /// Evicts entries until the limits are satisfied. Locked entries are never evicted.
+    async fn evict(&self, index: &mut Index<K>) {
+        let mut evicted = 0;
+        let mut skipped = None; // the rank of the last locked entry
+        while self.over_limits(index) {
+            let next = match skipped {
+                None => index.eviction_order.iter().next(),
+                Some(rank) => index.eviction_order.range((Bound::Excluded(rank), Bound::Unbounded)).next(),
+            };
+            let Some((rank, key)) = next.map(|(rank, key)| (*rank, key.clone())) else {
+                break;
+            };
+            if self.data.remove(&key).await {
+                index.remove(&key);
+                evicted += 1;
+            } else {
+                skipped = Some(rank);
+            }
+        }
+        if evicted != 0 {
+            let total = self.evictions.fetch_add(evicted, Ordering::Relaxed) + evicted;
+            info!(
+                "Evicted {evicted} cache entries ({:?}), now {} entries, {} bytes; {total} evicted in total.",
+                index.policy, index.entries.len(), index.bytes,
+            );
+        }
+    }
+}
+
+impl<K, V> MemCache<K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + ByteSize + std::marker::Sync + std::marker::Send,
+    V: ByteSize + std::marker::Sync + std::marker::Send,
+{
+    /// Stores `value` into the locked entry `guard` of `key`.
+    async fn store(&self, guard: &mut OwnedMutexGuard<Option<V>>, key: &K, value: Option<V>, options: PutOptions) {
+        let mut index = self.index.lock().await;
+        if let Some(value) = &value {
+            let stored_at = SystemTime::now();
+            let metadata = EntryMetadata {
+                stored_at,
+                expires_at: stored_at + options.ttl.unwrap_or(self.keep_duration),
+                size: value.byte_size(),
+                upstream_host: options.upstream_host,
+            };
+            index.insert(key.clone(), metadata, key.byte_size() + value.byte_size());
+        } else {
+            index.remove(key);
+        }
+        if self.over_limits(&index) {
+            self.evict(&mut index).await;
+        }
+        *guard.deref_mut() = value;
+    }
+}
+
+pub struct MemCacheGuard<'a, K: Hash + std::cmp::Eq, V> {
+    guard: OwnedMutexGuard<Option<V>>,
+    key: K,
+    cache: &'a MemCache<K, V>,
+}
+
+impl<'a, K: Hash + std::cmp::Eq, V> Deref for MemCacheGuard<'a, K, V> {
+    type Target = Option<V>;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+impl<'a, K: Hash + std::cmp::Eq, V> Drop for MemCacheGuard<'a, K, V> {
+    fn drop(&mut self) {
+        self.cache.data.remove_unused(&self.key, &self.guard);
+    }
+}
+
+#[async_trait]
+impl<'a, K, V> MutexGuard<Option<V>> for MemCacheGuard<'a, K, V>
+where
+    K: Clone + Hash + std::cmp::Eq + ByteSize + std::marker::Sync + std::marker::Send,
+    V: ByteSize + std::marker::Sync + std::marker::Send,
+{
+    async fn set_with_ttl(&mut self, value: Option<V>, ttl: Option<Duration>) {
+        self.cache.store(&mut self.guard, &self.key, value, PutOptions { ttl, upstream_host: None }).await;
+    }
+
+    async fn set_with_options(&mut self, value: Option<V>, options: PutOptions) where V: 'static {
+        self.cache.store(&mut self.guard, &self.key, value, options).await;
+    }
+
+    async fn inner(&self) -> Option<V> where Option<V>: Sized + Clone + std::marker::Sync
+    {
+        self.guard.inner().await
+    }
 }
 
 #[async_trait]
 impl<K, V> Cache<K, V> for MemCache<K, V>
 where
     // TODO: superfluous conditions?
-    K: Clone + Hash + std::cmp::Eq + std::marker::Sync + std::marker::Send,
-    V: std::marker::Send,
+    K: Clone + Hash + std::cmp::Eq + ByteSize + std::marker::Sync + std::marker::Send + serde::Serialize,
+    V: Clone + ByteSize + std::marker::Sync + std::marker::Send + serde::Serialize + 'static,
 {
-    async fn lock<'a>(&'a mut self, key: &K) -> MyResult<Box<dyn MutexGuard<Option<V>> + 'a>>
+    async fn lock<'a>(&'a self, key: &K) -> MyResult<Box<dyn MutexGuard<Option<V>> + Send + 'a>>
         where V: 'a
     {
-        // Remove expired entries.
-        let time_threshold = SystemTime::now() - self.keep_duration;
-
-        let mut put_times = self.put_times.lock().await; // a short-time lock
-        while let Some(kv) = put_times.first_key_value() {
-            if *kv.0 < time_threshold {
-                for kv2 in kv.1 {
-                    self.data.remove(kv2).await;
-                }
-                put_times.pop_first();
-            }
+        // Expired entries are removed by the reaper task, not here.
+        let guard = self.data.lock(key).await;
+        self.count_access(guard.is_some());
+        if guard.is_some() {
+            self.index.lock().await.touch(key);
+        }
+        Ok(Box::new(MemCacheGuard {
+            guard,
+            key: key.clone(),
+            cache: self,
+        }))
+    }
+
+    /// An entry locked at the moment (being fetched from upstream or read) is reported missing.
+    async fn get(&self, key: &K) -> MyResult<Option<V>> {
+        let value = self.data.try_get(key);
+        self.count_access(value.is_some());
+        if value.is_some() {
+            self.index.lock().await.touch(key);
         }
+        Ok(value)
+    }
+
+    async fn put(&self, key: &K, value: V, options: PutOptions) -> MyResult<()> {
+        let mut guard = self.data.lock(key).await;
+        self.store(&mut guard, key, Some(value), options).await;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &K) -> MyResult<bool> {
+        let mut guard = self.data.lock(key).await;
+        let existed = guard.is_some();
+        self.store(&mut guard, key, None, PutOptions::default()).await;
+        drop(guard);
+        self.data.remove(key).await;
+        Ok(existed)
+    }
+
+    async fn keys(&self) -> MyResult<Vec<K>> {
+        Ok(self.index.lock().await.entries.keys().cloned().collect())
+    }
+
+    async fn stats(&self) -> MyResult<CacheStats> {
+        let index = self.index.lock().await;
+        Ok(CacheStats {
+            entries: index.entries.len() as u64,
+            bytes: index.bytes as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        })
+    }
 
-        let data = self.data.lock(key).await;
-        Ok(Box::new(data))
+    async fn metadata(&self, key: &K) -> MyResult<Option<EntryMetadata>> {
+        Ok(self.index.lock().await.entries.get(key).map(|info| info.metadata.clone()))
+    }
+
+    async fn flush(&self) -> MyResult<()> {
+        if let Some(snapshot_file) = &self.snapshot_file {
+            self.save_snapshot(snapshot_file).await?;
+        }
+        Ok(())
     }
 }
 
-pub type BinaryMemCache = MemCache<Vec<u8>, Vec<u8>>;
\ No newline at end of file
+pub type BinaryMemCache = MemCache<Vec<u8>, Bytes>;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use bytes::Bytes;
+
+    use crate::cache::cache::{Cache, CacheStats, PutOptions};
+    use crate::config::EvictionPolicy;
+
+    use super::{BinaryMemCache, LegacySnapshotEntry};
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let snapshot_file = std::env::temp_dir().join(format!("join-proxy-snapshot-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&snapshot_file);
+
+        let cache = BinaryMemCache::with_snapshot(Duration::from_secs(60), snapshot_file.clone()).unwrap();
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        cache.flush().await.unwrap();
+
+        let cache = BinaryMemCache::with_snapshot(Duration::from_secs(60), snapshot_file.clone()).unwrap();
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+
+        // Outdated entries are not loaded.
+        let entries = vec![LegacySnapshotEntry {
+            put_time: SystemTime::now() - Duration::from_secs(120),
+            key: b"old".to_vec(),
+            value: Bytes::from_static(b"value"),
+        }];
+        std::fs::write(&snapshot_file, bincode::serialize(&entries).unwrap()).unwrap();
+        let cache = BinaryMemCache::with_snapshot(Duration::from_secs(60), snapshot_file.clone()).unwrap();
+        assert_eq!(cache.lock(&b"old".to_vec()).await.unwrap().inner().await, None);
+
+        std::fs::remove_file(&snapshot_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_over_limits() {
+        let snapshot_file = std::env::temp_dir().join(format!("join-proxy-snapshot-limits-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&snapshot_file);
+
+        let cache = BinaryMemCache::with_snapshot(Duration::from_secs(60), snapshot_file.clone()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            cache.lock(&key.to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        }
+        cache.flush().await.unwrap();
+
+        let cache = BinaryMemCache::with_snapshot(Duration::from_secs(60), snapshot_file.clone()).unwrap()
+            .with_limits(Some(2), None, EvictionPolicy::Lru);
+        cache.trim().await;
+        assert_eq!(cache.index.lock().await.entries.len(), 2);
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 1);
+
+        std::fs::remove_file(&snapshot_file).unwrap();
+    }
+
+    /// Simulates a request: waits for its key, "fetches" for `delay`, and stores the result.
+    async fn slow_request(cache: &BinaryMemCache, key: &[u8], delay: Duration) -> Option<Bytes> {
+        let mut guard = cache.lock(&key.to_vec()).await.unwrap();
+        if let Some(value) = guard.inner().await {
+            return Some(value);
+        }
+        tokio::time::sleep(delay).await;
+        guard.set(Some(Bytes::from_static(b"value"))).await;
+        None
+    }
+
+    #[tokio::test]
+    async fn test_different_keys_in_parallel() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let delay = Duration::from_millis(300);
+
+        let start = Instant::now();
+        let (res1, res2) = tokio::join!(
+            slow_request(&cache, b"key1", delay),
+            slow_request(&cache, b"key2", delay),
+        );
+        assert_eq!((res1, res2), (None, None));
+        assert!(start.elapsed() < 2 * delay);
+    }
+
+    #[tokio::test]
+    async fn test_same_key_waits() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let delay = Duration::from_millis(300);
+
+        let start = Instant::now();
+        let (res1, res2) = tokio::join!(
+            slow_request(&cache, b"key", delay),
+            slow_request(&cache, b"key", delay),
+        );
+        // The second request waits for the first one and gets its result.
+        assert_eq!((res1, res2), (None, Some(Bytes::from_static(b"value"))));
+        assert!(start.elapsed() >= delay);
+        assert!(start.elapsed() < 2 * delay);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let cache = Arc::new(BinaryMemCache::new(Duration::from_millis(100)));
+        cache.spawn_reaper(Duration::from_millis(10));
+
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, None);
+        assert!(cache.index.lock().await.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_of_overwritten_entry() {
+        let cache = BinaryMemCache::new(Duration::from_millis(100));
+
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"old"))).await;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"new"))).await;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // The first put has expired, but not the second one.
+        cache.remove_expired().await;
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"new")));
+    }
+
+    #[tokio::test]
+    async fn test_locked_entry_is_not_expired() {
+        let cache = BinaryMemCache::new(Duration::from_millis(50));
+
+        cache.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let guard = cache.lock(&b"key".to_vec()).await.unwrap();
+        cache.remove_expired().await;
+        assert_eq!(guard.inner().await, Some(Bytes::from_static(b"value")));
+        drop(guard);
+
+        // Removed as soon as it is released.
+        cache.remove_expired().await;
+        assert_eq!(cache.lock(&b"key".to_vec()).await.unwrap().inner().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60)).with_limits(Some(2), None, EvictionPolicy::Lru);
+        for key in [b"a", b"b"] {
+            cache.lock(&key.to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        }
+        cache.lock(&b"a".to_vec()).await.unwrap(); // "b" is now the least recently used
+        cache.lock(&b"c".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+
+        assert!(cache.lock(&b"a".to_vec()).await.unwrap().is_some());
+        assert!(cache.lock(&b"b".to_vec()).await.unwrap().is_none());
+        assert!(cache.lock(&b"c".to_vec()).await.unwrap().is_some());
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lfu_eviction() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60)).with_limits(Some(2), None, EvictionPolicy::Lfu);
+        for key in [b"a", b"b"] {
+            cache.lock(&key.to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        }
+        for _ in 0..3 {
+            cache.lock(&b"b".to_vec()).await.unwrap();
+        }
+        cache.lock(&b"a".to_vec()).await.unwrap(); // recently used, but less frequently than "b"
+        cache.lock(&b"c".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+
+        assert!(cache.lock(&b"a".to_vec()).await.unwrap().is_none());
+        assert!(cache.lock(&b"b".to_vec()).await.unwrap().is_some());
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes() {
+        // Keys take 1 byte, values 9 bytes.
+        let cache = BinaryMemCache::new(Duration::from_secs(60)).with_limits(None, Some(25), EvictionPolicy::Lru);
+        for key in [b"a", b"b", b"c"] {
+            cache.lock(&key.to_vec()).await.unwrap().set(Some(Bytes::from_static(b"123456789"))).await;
+        }
+        assert!(cache.lock(&b"a".to_vec()).await.unwrap().is_none());
+        assert_eq!(cache.index.lock().await.bytes, 20);
+    }
+
+    #[tokio::test]
+    async fn test_locked_entry_is_not_evicted() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60)).with_limits(Some(1), None, EvictionPolicy::Lru);
+        cache.lock(&b"a".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+
+        let guard = cache.lock(&b"a".to_vec()).await.unwrap();
+        cache.lock(&b"b".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert!(guard.is_some());
+        drop(guard);
+
+        // The limit is temporarily exceeded, "a" is evicted on the next insertion.
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 0);
+        cache.lock(&b"c".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert!(cache.lock(&b"a".to_vec()).await.unwrap().is_none());
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unused_lock_is_removed() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let key = b"key".to_vec();
+        let guard = cache.lock(&key).await.unwrap();
+        let (waiter, _) = tokio::join!(
+            cache.lock(&key),
+            async { drop(guard) }, // nothing stored
+        );
+        assert!(!cache.data.is_empty()); // still locked by the waiter
+        drop(waiter);
+        assert!(cache.data.is_empty());
+
+        cache.lock(&key).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert!(!cache.data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_put_with_ttl() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let options = PutOptions { ttl: Some(Duration::from_millis(50)), upstream_host: None };
+        cache.put(&b"short".to_vec(), Bytes::from_static(b"value"), options).await.unwrap();
+        cache.put(&b"long".to_vec(), Bytes::from_static(b"value"), PutOptions::default()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cache.remove_expired().await;
+        assert_eq!(cache.get(&b"short".to_vec()).await.unwrap(), None);
+        assert_eq!(cache.get(&b"long".to_vec()).await.unwrap(), Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_get_does_not_wait() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        cache.put(&b"key".to_vec(), Bytes::from_static(b"value"), PutOptions::default()).await.unwrap();
+
+        let guard = cache.lock(&b"key".to_vec()).await.unwrap();
+        assert_eq!(cache.get(&b"key".to_vec()).await.unwrap(), None);
+        drop(guard);
+        assert_eq!(cache.get(&b"key".to_vec()).await.unwrap(), Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_keys() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        for key in [b"a", b"b"] {
+            cache.put(&key.to_vec(), Bytes::from_static(b"value"), PutOptions::default()).await.unwrap();
+        }
+        assert!(cache.remove(&b"a".to_vec()).await.unwrap());
+        assert!(!cache.remove(&b"a".to_vec()).await.unwrap());
+        assert_eq!(cache.keys().await.unwrap(), vec![b"b".to_vec()]);
+        assert_eq!(cache.get(&b"a".to_vec()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stats_and_metadata() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let options = PutOptions { ttl: Some(Duration::from_secs(10)), upstream_host: Some("api.openai.com".to_string()) };
+        cache.put(&b"key".to_vec(), Bytes::from_static(b"value"), options).await.unwrap();
+        assert!(cache.lock(&b"key".to_vec()).await.unwrap().is_some());
+        assert!(cache.lock(&b"missing".to_vec()).await.unwrap().is_none());
+
+        let metadata = cache.metadata(&b"key".to_vec()).await.unwrap().unwrap();
+        assert_eq!(metadata.expires_at.duration_since(metadata.stored_at).unwrap(), Duration::from_secs(10));
+        assert_eq!(metadata.size, 5);
+        assert_eq!(metadata.upstream_host.as_deref(), Some("api.openai.com"));
+        assert_eq!(cache.metadata(&b"missing".to_vec()).await.unwrap(), None);
+        assert_eq!(cache.stats().await.unwrap(), CacheStats { entries: 1, bytes: 8, hits: 1, misses: 1, evictions: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_metadata_of_locked_entry() {
+        let cache = BinaryMemCache::new(Duration::from_secs(60));
+        let options = PutOptions { ttl: Some(Duration::from_secs(10)), upstream_host: Some("api.openai.com".to_string()) };
+        cache.lock(&b"key".to_vec()).await.unwrap().set_with_options(Some(Bytes::from_static(b"value")), options).await;
+
+        let metadata = cache.metadata(&b"key".to_vec()).await.unwrap().unwrap();
+        assert_eq!(metadata.expires_at.duration_since(metadata.stored_at).unwrap(), Duration::from_secs(10));
+        assert_eq!(metadata.upstream_host.as_deref(), Some("api.openai.com"));
+    }
+}
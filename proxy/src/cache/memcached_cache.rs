@@ -0,0 +1,358 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+use super::lockable_map::MutexGuard;
+use crate::{cache::cache::Cache, config::MemcachedCacheConfig, errors::{MemcachedError, MyResult}};
+
+/// Relative expiration times above this are interpreted by memcached as UNIX time.
+const MAX_RELATIVE_EXPIRATION: u64 = 30 * 24 * 3600;
+
+/// A minimal client of the memcached text protocol.
+///
+/// Each command takes an idle connection (or opens one), which is returned after a complete response.
+/// A connection whose command failed or was cancelled midway is dropped, so that a later command
+/// never reads a response left over from another one.
+struct MemcachedClient {
+    address: String,
+    idle: std::sync::Mutex<Vec<BufStream<TcpStream>>>,
+}
+
+impl MemcachedClient {
+    fn new(address: String) -> Self {
+        Self { address, idle: std::sync::Mutex::new(Vec::new()) }
+    }
+
+    /// Sends `command` (with `data` as a data block, if any) and reads the response.
+    /// Returns the response line and, for `get`, the value.
+    async fn request(&self, command: &[u8], data: Option<&[u8]>) -> MyResult<(String, Option<Bytes>)> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => BufStream::new(TcpStream::connect(&self.address).await?),
+        };
+        let result = Self::exchange(&mut connection, command, data).await;
+        if result.is_ok() {
+            self.idle.lock().unwrap().push(connection);
+        }
+        result
+    }
+
+    async fn exchange(stream: &mut BufStream<TcpStream>, command: &[u8], data: Option<&[u8]>) -> MyResult<(String, Option<Bytes>)> {
+        stream.write_all(command).await?;
+        stream.write_all(b"\r\n").await?;
+        if let Some(data) = data {
+            stream.write_all(data).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+        stream.flush().await?;
+
+        let mut line = Self::read_line(stream).await?;
+        let mut value = None;
+        if let Some(header) = line.strip_prefix("VALUE ") {
+            // VALUE <key> <flags> <bytes>
+            let len: usize = header.split(' ').nth(2).and_then(|len| len.parse().ok())
+                .ok_or_else(|| MemcachedError(format!("invalid response: {line}")))?;
+            let mut buf = vec![0; len + 2];
+            stream.read_exact(&mut buf).await?;
+            buf.truncate(len);
+            value = Some(buf.into());
+            line = Self::read_line(stream).await?;
+        }
+        if line.starts_with("ERROR") || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+            return Err(MemcachedError(line).into());
+        }
+        Ok((line, value))
+    }
+
+    async fn read_line(stream: &mut BufStream<TcpStream>) -> MyResult<String> {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(MemcachedError("connection closed".to_string()).into());
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    async fn get(&self, key: &str) -> MyResult<Option<Bytes>> {
+        Ok(self.request(format!("get {key}").as_bytes(), None).await?.1)
+    }
+
+    /// `command` is `set` or `add`. Returns whether the value was stored.
+    async fn store(&self, command: &str, key: &str, value: &[u8], expiration: Duration) -> MyResult<bool> {
+        let header = format!("{command} {key} 0 {} {}", expiration_time(expiration), value.len());
+        let (line, _) = self.request(header.as_bytes(), Some(value)).await?;
+        Ok(line == "STORED")
+    }
+
+    async fn delete(&self, key: &str) -> MyResult<()> {
+        self.request(format!("delete {key}").as_bytes(), None).await?;
+        Ok(())
+    }
+}
+
+fn expiration_time(expiration: Duration) -> u64 {
+    let secs = expiration.as_secs().max(1);
+    if secs <= MAX_RELATIVE_EXPIRATION {
+        secs
+    } else {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() + secs
+    }
+}
+
+/// Cache shared between several proxy instances through memcached.
+///
+/// The "one upstream request per key" lock is a key stored with `add`, that fails if it already exists.
+/// Entries expire by memcached's own TTL.
+pub struct MemcachedCache {
+    client: Arc<MemcachedClient>,
+    key_prefix: String,
+    keep_duration: Duration,
+    lock_timeout: Duration,
+    lock_poll_interval: Duration,
+    locked_reads: bool,
+}
+
+impl MemcachedCache {
+    pub fn new(config: &MemcachedCacheConfig, keep_duration: Duration) -> Self {
+        Self {
+            client: Arc::new(MemcachedClient::new(config.address.clone())),
+            key_prefix: config.key_prefix.clone(),
+            keep_duration,
+            lock_timeout: config.lock_timeout,
+            lock_poll_interval: config.lock_poll_interval,
+            locked_reads: false,
+        }
+    }
+
+    /// Takes the lock even if the value is there, for values kept after they expire,
+    /// that the lock holder may fetch again.
+    pub fn with_locked_reads(mut self) -> Self {
+        self.locked_reads = true;
+        self
+    }
+
+    // Memcached keys can't contain whitespace or control characters, so binary keys are hex encoded.
+    fn memcached_key(&self, kind: &str, key: &[u8]) -> String {
+        let hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{}{kind}:{hex}", self.key_prefix)
+    }
+}
+
+#[async_trait]
+impl Cache<Vec<u8>, Bytes> for MemcachedCache {
+    async fn lock<'a>(&'a self, key: &Vec<u8>) -> MyResult<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>
+        where Bytes: 'a
+    {
+        let data_key = self.memcached_key("data", key);
+
+        // Fast path: no need to lock, if the value is already there.
+        if let Some(value) = self.client.get(&data_key).await?.filter(|_| !self.locked_reads) {
+            return Ok(Box::new(MemcachedGuard {
+                client: self.client.clone(),
+                data_key,
+                lock_key: None,
+                value: Some(value),
+                keep_duration: self.keep_duration,
+            }));
+        }
+
+        let lock_key = self.memcached_key("lock", key);
+        while !self.client.store("add", &lock_key, b"", self.lock_timeout).await? {
+            tokio::time::sleep(self.lock_poll_interval).await;
+        }
+
+        // Another instance may have filled the value while we were waiting.
+        let value = self.client.get(&data_key).await?;
+        Ok(Box::new(MemcachedGuard {
+            client: self.client.clone(),
+            data_key,
+            lock_key: Some(lock_key),
+            value,
+            keep_duration: self.keep_duration,
+        }))
+    }
+}
+
+pub struct MemcachedGuard {
+    client: Arc<MemcachedClient>,
+    data_key: String,
+    // Unlike Redis, memcached can't delete a key only if it has our value.
+    // This is safe as long as `lock_timeout` exceeds the time to fetch from upstream.
+    lock_key: Option<String>,
+    value: Option<Bytes>,
+    keep_duration: Duration,
+}
+
+async fn unlock(client: Arc<MemcachedClient>, lock_key: String) {
+    if let Err(e) = client.delete(&lock_key).await {
+        warn!("Cannot release memcached lock: {e}");
+    }
+}
+
+impl Deref for MemcachedGuard {
+    type Target = Option<Bytes>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[async_trait]
+impl MutexGuard<Option<Bytes>> for MemcachedGuard {
+    async fn set_with_ttl(&mut self, value: Option<Bytes>, ttl: Option<Duration>) {
+        let res = if let Some(value) = &value {
+            self.client.store("set", &self.data_key, value, ttl.unwrap_or(self.keep_duration)).await.map(|_| ())
+        } else {
+            self.client.delete(&self.data_key).await
+        };
+        if let Err(e) = res {
+            warn!("Cannot store value in memcached: {e}");
+        }
+        self.value = value;
+
+        // The value is stored, let waiting instances proceed.
+        if let Some(lock_key) = self.lock_key.take() {
+            unlock(self.client.clone(), lock_key).await;
+        }
+    }
+
+    async fn inner(&self) -> Option<Bytes> {
+        self.value.clone()
+    }
+}
+
+impl Drop for MemcachedGuard {
+    fn drop(&mut self) {
+        if let Some(lock_key) = self.lock_key.take() {
+            tokio::spawn(unlock(self.client.clone(), lock_key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Child, Command};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+
+    use crate::cache::cache::Cache;
+    use crate::config::MemcachedCacheConfig;
+
+    use super::{MemcachedCache, MemcachedClient};
+
+    struct MemcachedServer(Child);
+
+    impl MemcachedServer {
+        fn start(port: u16) -> Self {
+            let child = Command::new("memcached")
+                .args(["--port", &port.to_string(), "--listen", "127.0.0.1"])
+                .spawn()
+                .expect("cannot start memcached");
+            std::thread::sleep(Duration::from_millis(500)); // Wait till the daemon starts.
+            Self(child)
+        }
+    }
+
+    impl Drop for MemcachedServer {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn config(port: u16) -> MemcachedCacheConfig {
+        MemcachedCacheConfig {
+            address: format!("127.0.0.1:{port}"),
+            key_prefix: "test:".to_string(),
+            lock_timeout: Duration::from_secs(10),
+            lock_poll_interval: Duration::from_millis(10),
+        }
+    }
+
+    /// Answers `get <key>` with the key as the value, slowly for the key `slow`.
+    async fn fake_memcached() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut stream = BufStream::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap() != 0 {
+                        let key = line.trim_end().strip_prefix("get ").unwrap().to_string();
+                        if key == "slow" {
+                            tokio::time::sleep(Duration::from_millis(200)).await;
+                        }
+                        let response = format!("VALUE {key} 0 {}\r\n{key}\r\nEND\r\n", key.len());
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                        stream.flush().await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request() {
+        let client = MemcachedClient::new(fake_memcached().await);
+        assert!(tokio::time::timeout(Duration::from_millis(50), client.get("slow")).await.is_err());
+        // The response to the cancelled request isn't taken for this one.
+        assert_eq!(client.get("fast").await.unwrap(), Some(Bytes::from_static(b"fast")));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.get("fast").await.unwrap(), Some(Bytes::from_static(b"fast")));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs memcached in PATH"]
+    async fn test_shared_lock() {
+        let _server = MemcachedServer::start(21211);
+        let cache1 = MemcachedCache::new(&config(21211), Duration::from_secs(60));
+        let cache2 = MemcachedCache::new(&config(21211), Duration::from_secs(60));
+        let key = b"key".to_vec();
+
+        let mut guard1 = cache1.lock(&key).await.unwrap();
+        assert!(guard1.inner().await.is_none());
+
+        // The second "instance" waits until the first one stores the value.
+        let waiter = async {
+            let guard2 = cache2.lock(&key).await.unwrap();
+            (Instant::now(), guard2.inner().await)
+        };
+        let setter = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let set_at = Instant::now();
+            guard1.set(Some(Bytes::from_static(b"value\r\nwith binary \x00 data"))).await;
+            drop(guard1);
+            set_at
+        };
+        let ((got_at, value), set_at) = tokio::join!(waiter, setter);
+        assert!(got_at >= set_at);
+        assert_eq!(value, Some(Bytes::from_static(b"value\r\nwith binary \x00 data")));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs memcached in PATH"]
+    async fn test_expiry() {
+        let _server = MemcachedServer::start(21212);
+        let cache = MemcachedCache::new(&config(21212), Duration::from_secs(1));
+        let key = b"key".to_vec();
+
+        cache.lock(&key).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert_eq!(cache.lock(&key).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        let guard = tokio::time::timeout(Duration::from_secs(1), cache.lock(&key)).await
+            .expect("lock was not released").unwrap();
+        assert!(guard.inner().await.is_none());
+    }
+}
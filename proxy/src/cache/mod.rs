@@ -1,3 +1,9 @@
 mod lockable_map;
 pub mod cache;
-pub mod mem_cache;
\ No newline at end of file
+pub mod mem_cache;
+pub mod redis_cache;
+pub mod memcached_cache;
+pub mod disk_cache;
+pub mod tiered_cache;
+pub mod compressed_cache;
+pub mod encrypted_cache;
\ No newline at end of file
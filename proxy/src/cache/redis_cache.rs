@@ -0,0 +1,282 @@
+use std::ops::Deref;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use rand::RngCore;
+use redis::{aio::ConnectionManager, Script};
+
+use super::lockable_map::MutexGuard;
+use crate::{cache::cache::Cache, config::RedisCacheConfig, errors::MyResult};
+
+// Delete the lock only if we still own it (it may have expired and been taken by another instance).
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
+
+/// Cache shared between several proxy instances.
+///
+/// The "one upstream request per key" lock is a Redis key set with `NX`,
+/// so only one instance fetches from upstream while the others wait.
+pub struct RedisCache {
+    connection: ConnectionManager,
+    key_prefix: Vec<u8>,
+    keep_duration: Duration,
+    lock_timeout: Duration,
+    lock_poll_interval: Duration,
+    locked_reads: bool,
+}
+
+impl RedisCache {
+    pub async fn new(config: &RedisCacheConfig, keep_duration: Duration) -> MyResult<Self> {
+        let client = redis::Client::open(config.url.as_str())?;
+        let connection = client.get_connection_manager().await?;
+        Ok(Self {
+            connection,
+            key_prefix: config.key_prefix.as_bytes().to_vec(),
+            keep_duration,
+            lock_timeout: config.lock_timeout,
+            lock_poll_interval: config.lock_poll_interval,
+            locked_reads: false,
+        })
+    }
+
+    /// Takes the lock even if the value is there, for values kept after they expire,
+    /// that the lock holder may fetch again.
+    pub fn with_locked_reads(mut self) -> Self {
+        self.locked_reads = true;
+        self
+    }
+
+    fn data_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.key_prefix.as_slice(), b"data:", key].concat()
+    }
+
+    fn lock_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.key_prefix.as_slice(), b"lock:", key].concat()
+    }
+
+    async fn get(&self, data_key: &[u8]) -> MyResult<Option<Bytes>> {
+        let mut connection = self.connection.clone();
+        let value: Option<Vec<u8>> = redis::cmd("GET").arg(data_key).query_async(&mut connection).await?;
+        Ok(value.map(Bytes::from))
+    }
+}
+
+#[async_trait]
+impl Cache<Vec<u8>, Bytes> for RedisCache {
+    async fn lock<'a>(&'a self, key: &Vec<u8>) -> MyResult<Box<dyn MutexGuard<Option<Bytes>> + Send + 'a>>
+        where Bytes: 'a
+    {
+        let data_key = self.data_key(key);
+
+        // Fast path: no need to lock, if the value is already there.
+        if let Some(value) = self.get(&data_key).await?.filter(|_| !self.locked_reads) {
+            return Ok(Box::new(RedisGuard {
+                connection: self.connection.clone(),
+                data_key,
+                lock: None,
+                value: Some(value),
+                keep_duration: self.keep_duration,
+            }));
+        }
+
+        let lock_key = self.lock_key(key);
+        let mut token = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = token.to_vec();
+        let mut connection = self.connection.clone();
+        loop {
+            let acquired: Option<String> = redis::cmd("SET")
+                .arg(&lock_key)
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(px(self.lock_timeout))
+                .query_async(&mut connection)
+                .await?;
+            if acquired.is_some() {
+                break;
+            }
+            tokio::time::sleep(self.lock_poll_interval).await;
+        }
+
+        // Another instance may have filled the value while we were waiting.
+        let value = self.get(&data_key).await?;
+        Ok(Box::new(RedisGuard {
+            connection,
+            data_key,
+            lock: Some((lock_key, token)),
+            value,
+            keep_duration: self.keep_duration,
+        }))
+    }
+}
+
+pub struct RedisGuard {
+    connection: ConnectionManager,
+    data_key: Vec<u8>,
+    lock: Option<(Vec<u8>, Vec<u8>)>, // lock key and our token
+    value: Option<Bytes>,
+    keep_duration: Duration,
+}
+
+async fn unlock(mut connection: ConnectionManager, lock_key: Vec<u8>, token: Vec<u8>) {
+    let res: redis::RedisResult<i32> = Script::new(UNLOCK_SCRIPT)
+        .key(lock_key)
+        .arg(token)
+        .invoke_async(&mut connection)
+        .await;
+    if let Err(e) = res {
+        warn!("Cannot release Redis lock: {e}");
+    }
+}
+
+impl Deref for RedisGuard {
+    type Target = Option<Bytes>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+/// The `PX` argument of `SET`. Redis rejects zero, so a TTL shorter than a millisecond is rounded up.
+fn px(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+#[async_trait]
+impl MutexGuard<Option<Bytes>> for RedisGuard {
+    async fn set_with_ttl(&mut self, value: Option<Bytes>, ttl: Option<Duration>) {
+        let res: redis::RedisResult<()> = if let Some(value) = &value {
+            redis::cmd("SET")
+                .arg(&self.data_key)
+                .arg(value.as_ref())
+                .arg("PX")
+                .arg(px(ttl.unwrap_or(self.keep_duration)))
+                .query_async(&mut self.connection)
+                .await
+        } else {
+            redis::cmd("DEL").arg(&self.data_key).query_async(&mut self.connection).await
+        };
+        if let Err(e) = res {
+            warn!("Cannot store value in Redis: {e}");
+        }
+        self.value = value;
+
+        // The value is stored, let waiting instances proceed.
+        if let Some((lock_key, token)) = self.lock.take() {
+            unlock(self.connection.clone(), lock_key, token).await;
+        }
+    }
+
+    async fn inner(&self) -> Option<Bytes> {
+        self.value.clone()
+    }
+}
+
+impl Drop for RedisGuard {
+    fn drop(&mut self) {
+        if let Some((lock_key, token)) = self.lock.take() {
+            tokio::spawn(unlock(self.connection.clone(), lock_key, token));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Child, Command};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use crate::cache::cache::Cache;
+    use crate::config::RedisCacheConfig;
+
+    use super::{px, RedisCache};
+
+    struct RedisServer(Child);
+
+    impl RedisServer {
+        fn start(port: u16) -> Self {
+            let child = Command::new("redis-server")
+                .args(["--port", &port.to_string(), "--save", "", "--appendonly", "no"])
+                .spawn()
+                .expect("cannot start redis-server");
+            std::thread::sleep(Duration::from_millis(500)); // Wait till the daemon starts.
+            Self(child)
+        }
+    }
+
+    impl Drop for RedisServer {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn config(port: u16) -> RedisCacheConfig {
+        RedisCacheConfig {
+            url: format!("redis://127.0.0.1:{port}/"),
+            key_prefix: "test:".to_string(),
+            lock_timeout: Duration::from_secs(10),
+            lock_poll_interval: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn test_px() {
+        assert_eq!(px(Duration::from_micros(100)), 1);
+        assert_eq!(px(Duration::ZERO), 1);
+        assert_eq!(px(Duration::from_secs(2)), 2000);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server in PATH"]
+    async fn test_shared_lock() {
+        let _server = RedisServer::start(16379);
+        let cache1 = RedisCache::new(&config(16379), Duration::from_secs(60)).await.unwrap();
+        let cache2 = RedisCache::new(&config(16379), Duration::from_secs(60)).await.unwrap();
+        let key = b"key".to_vec();
+
+        let mut guard1 = cache1.lock(&key).await.unwrap();
+        assert!(guard1.inner().await.is_none());
+
+        // The second "instance" waits until the first one stores the value.
+        let waiter = async {
+            let guard2 = cache2.lock(&key).await.unwrap();
+            (Instant::now(), guard2.inner().await)
+        };
+        let setter = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let set_at = Instant::now();
+            guard1.set(Some(Bytes::from_static(b"value"))).await;
+            drop(guard1);
+            set_at
+        };
+        let ((got_at, value), set_at) = tokio::join!(waiter, setter);
+        assert!(got_at >= set_at);
+        assert_eq!(value, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis-server in PATH"]
+    async fn test_lock_released_on_drop() {
+        let _server = RedisServer::start(16380);
+        let cache = RedisCache::new(&config(16380), Duration::from_secs(60)).await.unwrap();
+        let key = b"key".to_vec();
+
+        let guard = cache.lock(&key).await.unwrap();
+        drop(guard); // e.g. upstream failed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let guard = tokio::time::timeout(Duration::from_secs(1), cache.lock(&key)).await
+            .expect("lock was not released").unwrap();
+        assert!(guard.inner().await.is_none());
+    }
+}
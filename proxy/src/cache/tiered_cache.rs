@@ -0,0 +1,255 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+
+use super::lockable_map::MutexGuard;
+use crate::{cache::cache::{Cache, CacheStats, EntryMetadata, PutOptions}, errors::MyResult};
+
+/// A small fast cache (L1, usually in memory) in front of a slower persistent or shared one (L2).
+///
+/// L1 is checked first. On an L1 miss, L2 is checked and L1 is filled from it.
+/// Values are written to both tiers.
+pub struct TieredCache<K, V> {
+    l1: Arc<dyn Cache<K, V>>,
+    l2: Arc<dyn Cache<K, V>>,
+    l1_max_ttl: Option<Duration>,
+    locked_reads: bool,
+}
+
+impl<K, V> TieredCache<K, V> {
+    pub fn new(l1: Arc<dyn Cache<K, V>>, l2: Arc<dyn Cache<K, V>>) -> Self {
+        Self { l1, l2, l1_max_ttl: None, locked_reads: false }
+    }
+
+    /// Locks L2 even on an L1 hit, for values kept after they expire, that the lock holder may fetch again.
+    /// The value is then read from L2, as another instance may have fetched it again.
+    pub fn with_locked_reads(mut self) -> Self {
+        self.locked_reads = true;
+        self
+    }
+
+    /// Entries are kept in L1 no longer than `l1_max_ttl`, even if their TTL is longer.
+    pub fn with_l1_max_ttl(mut self, l1_max_ttl: Duration) -> Self {
+        self.l1_max_ttl = Some(l1_max_ttl);
+        self
+    }
+
+    fn l1_ttl(&self, ttl: Option<Duration>) -> Option<Duration> {
+        match (ttl, self.l1_max_ttl) {
+            (Some(ttl), Some(max)) => Some(ttl.min(max)),
+            (ttl, _) => ttl,
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> Cache<K, V> for TieredCache<K, V>
+where
+    K: Clone + std::marker::Sync + std::marker::Send,
+    V: Clone + PartialEq + std::marker::Sync + std::marker::Send + 'static,
+{
+    async fn lock<'a>(&'a self, key: &K) -> MyResult<Box<dyn MutexGuard<Option<V>> + Send + 'a>>
+        where V: 'a
+    {
+        // The L1 lock also makes requests to this instance wait for each other before reaching L2.
+        let mut l1 = self.l1.lock(key).await?;
+        let l1_value = l1.inner().await;
+        if l1_value.is_some() && !self.locked_reads {
+            return Ok(Box::new(TieredGuard::new(l1, None, l1_value, key.clone(), self)));
+        }
+
+        let l2 = self.l2.lock(key).await?;
+        let value = l2.inner().await;
+        if value.is_some() && value != l1_value {
+            // The remaining TTL in L2 isn't known, so L1 keeps the value for its own timeout.
+            // Responses that expire sooner are checked against their `FetchInfo` when served.
+            l1.set(value.clone()).await;
+        }
+        Ok(Box::new(TieredGuard::new(l1, Some(l2), value, key.clone(), self)))
+    }
+
+    async fn get(&self, key: &K) -> MyResult<Option<V>> {
+        match self.l1.get(key).await? {
+            Some(value) => Ok(Some(value)),
+            None => self.l2.get(key).await,
+        }
+    }
+
+    async fn put(&self, key: &K, value: V, options: PutOptions) -> MyResult<()> {
+        let l1_options = PutOptions { ttl: self.l1_ttl(options.ttl), ..options.clone() };
+        self.l2.put(key, value.clone(), options).await?;
+        self.l1.put(key, value, l1_options).await
+    }
+
+    async fn remove(&self, key: &K) -> MyResult<bool> {
+        let l2 = self.l2.remove(key).await?;
+        let l1 = self.l1.remove(key).await?;
+        Ok(l1 || l2)
+    }
+
+    /// L2 has all the entries of L1, so keys, stats, and metadata are of L2.
+    async fn keys(&self) -> MyResult<Vec<K>> {
+        self.l2.keys().await
+    }
+
+    async fn stats(&self) -> MyResult<CacheStats> {
+        self.l2.stats().await
+    }
+
+    async fn metadata(&self, key: &K) -> MyResult<Option<EntryMetadata>> {
+        self.l2.metadata(key).await
+    }
+
+    async fn flush(&self) -> MyResult<()> {
+        self.l1.flush().await?;
+        self.l2.flush().await
+    }
+}
+
+type BoxedGuard<'a, V> = Box<dyn MutexGuard<Option<V>> + Send + 'a>;
+
+/// The tier guards are only `Send`, so they are kept behind a `std::sync::Mutex` (accessed only with `get_mut`)
+/// and reads are served from `value`.
+pub struct TieredGuard<'a, K, V> {
+    l1: std::sync::Mutex<BoxedGuard<'a, V>>,
+    l2: std::sync::Mutex<Option<BoxedGuard<'a, V>>>, // locked only on L1 miss
+    value: Option<V>,
+    key: K,
+    cache: &'a TieredCache<K, V>,
+}
+
+impl<'a, K, V> TieredGuard<'a, K, V> {
+    fn new(l1: BoxedGuard<'a, V>, l2: Option<BoxedGuard<'a, V>>, value: Option<V>, key: K, cache: &'a TieredCache<K, V>) -> Self {
+        Self { l1: std::sync::Mutex::new(l1), l2: std::sync::Mutex::new(l2), value, key, cache }
+    }
+}
+
+impl<'a, K, V> Deref for TieredGuard<'a, K, V> {
+    type Target = Option<V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[async_trait]
+impl<'a, K, V> MutexGuard<Option<V>> for TieredGuard<'a, K, V>
+where
+    K: Clone + std::marker::Sync + std::marker::Send,
+    V: Clone + std::marker::Sync + std::marker::Send + 'static,
+{
+    async fn set_with_ttl(&mut self, value: Option<V>, ttl: Option<Duration>) {
+        self.set_with_options(value, PutOptions { ttl, upstream_host: None }).await;
+    }
+
+    async fn set_with_options(&mut self, value: Option<V>, options: PutOptions) where V: 'static {
+        let l1_options = PutOptions { ttl: self.cache.l1_ttl(options.ttl), ..options.clone() };
+        let l2 = self.l2.get_mut().unwrap();
+        if l2.is_none() {
+            match self.cache.l2.lock(&self.key).await {
+                Ok(guard) => *l2 = Some(guard),
+                Err(e) => warn!("Cannot lock L2 cache: {e}"),
+            }
+        }
+        if let Some(l2) = l2 {
+            l2.set_with_options(value.clone(), options).await;
+        }
+        self.l1.get_mut().unwrap().set_with_options(value.clone(), l1_options).await;
+        self.value = value;
+    }
+
+    async fn inner(&self) -> Option<V> where Option<V>: Sized + Clone + std::marker::Sync
+    {
+        self.value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::cache::{cache::{Cache, PutOptions}, mem_cache::BinaryMemCache};
+
+    use super::TieredCache;
+
+    type BinaryTieredCache = TieredCache<Vec<u8>, Bytes>;
+
+    fn caches() -> (Arc<BinaryMemCache>, Arc<BinaryMemCache>, BinaryTieredCache) {
+        let l1 = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let l2 = Arc::new(BinaryMemCache::new(Duration::from_secs(60)));
+        let tiered = TieredCache::new(l1.clone(), l2.clone());
+        (l1, l2, tiered)
+    }
+
+    #[tokio::test]
+    async fn test_write_to_both() {
+        let (l1, l2, tiered) = caches();
+        tiered.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert_eq!(l1.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+        assert_eq!(l2.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_l1_filled_from_l2() {
+        let (l1, l2, tiered) = caches();
+        l2.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        assert_eq!(tiered.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+        assert_eq!(l1.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_l1_hit_does_not_reach_l2() {
+        let (l1, l2, tiered) = caches();
+        l1.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"value"))).await;
+        let _l2_guard = l2.lock(&b"key".to_vec()).await.unwrap(); // would block the tiered cache
+        let guard = tokio::time::timeout(Duration::from_secs(1), tiered.lock(&b"key".to_vec())).await
+            .expect("L2 was locked").unwrap();
+        assert_eq!(guard.inner().await, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_locked_reads() {
+        let (l1, l2, tiered) = caches();
+        let tiered = tiered.with_locked_reads();
+        l1.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"old"))).await;
+        l2.lock(&b"key".to_vec()).await.unwrap().set(Some(Bytes::from_static(b"new"))).await;
+
+        let l2_guard = l2.lock(&b"key".to_vec()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), tiered.lock(&b"key".to_vec())).await.is_err());
+        drop(l2_guard);
+
+        // L2 may have been updated by another instance.
+        assert_eq!(tiered.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"new")));
+        assert_eq!(l1.lock(&b"key".to_vec()).await.unwrap().inner().await, Some(Bytes::from_static(b"new")));
+    }
+
+    #[tokio::test]
+    async fn test_l1_max_ttl() {
+        let (l1, l2, tiered) = caches();
+        let tiered = tiered.with_l1_max_ttl(Duration::from_secs(10));
+        tiered.lock(&b"key".to_vec()).await.unwrap().set_with_ttl(Some(Bytes::from_static(b"value")), Some(Duration::from_secs(3600))).await;
+
+        let l1_metadata = l1.metadata(&b"key".to_vec()).await.unwrap().unwrap();
+        let l2_metadata = l2.metadata(&b"key".to_vec()).await.unwrap().unwrap();
+        assert_eq!(l1_metadata.expires_at.duration_since(l1_metadata.stored_at).unwrap(), Duration::from_secs(10));
+        assert_eq!(l2_metadata.expires_at.duration_since(l2_metadata.stored_at).unwrap(), Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_host() {
+        let (l1, l2, tiered) = caches();
+        let options = PutOptions { ttl: None, upstream_host: Some("api.openai.com".to_string()) };
+        tiered.lock(&b"key".to_vec()).await.unwrap().set_with_options(Some(Bytes::from_static(b"value")), options).await;
+
+        for tier in [l1, l2] {
+            let metadata = tier.metadata(&b"key".to_vec()).await.unwrap().unwrap();
+            assert_eq!(metadata.upstream_host.as_deref(), Some("api.openai.com"));
+        }
+    }
+}
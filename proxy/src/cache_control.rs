@@ -0,0 +1,124 @@
+use std::time::{Duration, SystemTime};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+
+use crate::cached_response::CachedResponse;
+
+/// What the upstream response headers say about storing it in a shared cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamFreshness {
+    /// `no-store` or `private`.
+    NoStore,
+    /// From `s-maxage`, `max-age`, or `Expires`, in this order of precedence.
+    MaxAge(Duration),
+    Unspecified,
+}
+
+impl UpstreamFreshness {
+    pub fn from_headers(headers: &HeaderMap, now: SystemTime) -> Self {
+        let (mut max_age, mut s_maxage) = (None, None);
+        let directives = headers.get_all(header::CACHE_CONTROL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            // An invalid age makes the response stale, as RFC 9111 recommends.
+            let seconds = || Duration::from_secs(value.and_then(|value| value.parse().ok()).unwrap_or(0));
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "private" => return UpstreamFreshness::NoStore,
+                "max-age" => max_age = Some(seconds()),
+                "s-maxage" => s_maxage = Some(seconds()),
+                _ => {}
+            }
+        }
+        if let Some(age) = s_maxage.or(max_age) {
+            return UpstreamFreshness::MaxAge(age);
+        }
+
+        let Some(expires) = headers.get(header::EXPIRES) else {
+            return UpstreamFreshness::Unspecified;
+        };
+        // Relative to the upstream's clock, if it tells it.
+        let date = headers.get(header::DATE)
+            .and_then(|date| httpdate::parse_http_date(date.to_str().ok()?).ok())
+            .unwrap_or(now);
+        // An invalid date, such as "0", means already expired.
+        let expires = expires.to_str().ok().and_then(|expires| httpdate::parse_http_date(expires).ok());
+        UpstreamFreshness::MaxAge(expires.and_then(|expires| expires.duration_since(date).ok()).unwrap_or_default())
+    }
+}
+
+/// Headers asking upstream to send the body only if it changed since `cached`, empty if it has no validators.
+pub fn conditional_headers(cached: &CachedResponse) -> Vec<(HeaderName, HeaderValue)> {
+    [(header::ETAG, header::IF_NONE_MATCH), (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE)].into_iter()
+        .filter_map(|(validator, condition)| {
+            let value = HeaderValue::from_bytes(cached.header(validator.as_str())?).ok()?;
+            Some((condition, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use bytes::Bytes;
+    use http::{HeaderMap, HeaderName, HeaderValue};
+
+    use crate::cached_response::CachedResponse;
+
+    use super::{conditional_headers, UpstreamFreshness};
+
+    #[test]
+    fn test_from_headers() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let date = httpdate::fmt_http_date(now);
+        let in_hour = httpdate::fmt_http_date(now + Duration::from_secs(3600));
+        let max_age = |secs| UpstreamFreshness::MaxAge(Duration::from_secs(secs));
+        let cases: &[(&[(&str, &str)], UpstreamFreshness)] = &[
+            (&[], UpstreamFreshness::Unspecified),
+            (&[("cache-control", "public")], UpstreamFreshness::Unspecified),
+            (&[("cache-control", "max-age=60")], max_age(60)),
+            (&[("cache-control", "public, Max-Age=\"60\"")], max_age(60)),
+            (&[("cache-control", "max-age=60, s-maxage=600")], max_age(600)),
+            (&[("cache-control", "s-maxage=600"), ("cache-control", "max-age=60")], max_age(600)),
+            (&[("cache-control", "max-age=soon")], max_age(0)),
+            (&[("cache-control", "max-age=60, no-store")], UpstreamFreshness::NoStore),
+            (&[("cache-control", "private")], UpstreamFreshness::NoStore),
+            (&[("cache-control", "private=\"set-cookie\"")], UpstreamFreshness::NoStore),
+            (&[("expires", &in_hour)], max_age(3600)),
+            (&[("expires", &in_hour), ("date", &date)], max_age(3600)),
+            (&[("expires", &date), ("date", &in_hour)], max_age(0)),
+            (&[("expires", "0")], max_age(0)),
+            (&[("expires", &in_hour), ("cache-control", "max-age=60")], max_age(60)),
+        ];
+        for (headers, expected) in cases {
+            let headers: HeaderMap = headers.iter()
+                .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_str(v).unwrap()))
+                .collect();
+            assert_eq!(UpstreamFreshness::from_headers(&headers, now), *expected, "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn test_conditional_headers() {
+        let response = |headers: &[(&str, &str)]| CachedResponse {
+            status: 200,
+            headers: headers.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect(),
+            body: Bytes::new(),
+            fetch_info: None,
+        };
+        let date = "Tue, 14 Nov 2023 22:13:20 GMT";
+        assert_eq!(conditional_headers(&response(&[("content-type", "text/plain")])), vec![]);
+        assert_eq!(
+            conditional_headers(&response(&[("ETag", "W/\"1\""), ("Last-Modified", date)])),
+            vec![
+                (HeaderName::from_static("if-none-match"), HeaderValue::from_static("W/\"1\"")),
+                (HeaderName::from_static("if-modified-since"), HeaderValue::from_static(date)),
+            ],
+        );
+    }
+}
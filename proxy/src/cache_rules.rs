@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use crate::{cache_control::UpstreamFreshness, config::{CacheConfig, CacheRule, FreshnessPolicy}};
+
+/// How long to cache the response to a request, `None` if it must not be cached.
+pub fn cache_ttl(config: &CacheConfig, method: &str, host: &str, path: &str) -> Option<Duration> {
+    match config.rules.iter().find(|rule| rule_matches(rule, method, host, path)) {
+        Some(rule) if rule.no_cache => None,
+        Some(rule) => Some(rule.ttl.unwrap_or(config.cache_timeout)),
+        None => Some(config.cache_timeout),
+    }
+}
+
+/// How long to cache a response with `status` to a request cached for `ttl`, `None` if it must not be cached.
+pub fn response_ttl(config: &CacheConfig, status: u16, ttl: Duration) -> Option<Duration> {
+    if !config.cacheable_statuses.iter().any(|pattern| pattern.matches(status)) {
+        return None;
+    }
+    match config.error_ttl {
+        Some(error_ttl) if status >= 400 => Some(ttl.min(error_ttl)),
+        _ => Some(ttl),
+    }
+}
+
+/// Applies the upstream's own freshness rules to `ttl`, if configured for `host`.
+/// `None` if the response must not be stored.
+pub fn upstream_ttl(config: &CacheConfig, host: &str, upstream: UpstreamFreshness, ttl: Duration) -> Option<Duration> {
+    let policy = config.freshness_per_host.get(host).copied().unwrap_or(config.freshness);
+    let ttl = match (policy, upstream) {
+        (FreshnessPolicy::Ignore, _) | (_, UpstreamFreshness::Unspecified) => ttl,
+        (_, UpstreamFreshness::NoStore) => return None,
+        (FreshnessPolicy::Respect, UpstreamFreshness::MaxAge(max_age)) => max_age,
+        (FreshnessPolicy::RespectMin, UpstreamFreshness::MaxAge(max_age)) => max_age.min(ttl),
+    };
+    Some(ttl).filter(|ttl| !ttl.is_zero())
+}
+
+fn rule_matches(rule: &CacheRule, method: &str, host: &str, path: &str) -> bool {
+    rule.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(method)) &&
+        rule.host.as_ref().is_none_or(|h| h.eq_ignore_ascii_case(host)) &&
+        rule.path.as_ref().is_none_or(|pattern| {
+            if pattern.contains(['*', '?']) {
+                glob_matches(pattern.as_bytes(), path.as_bytes())
+            } else {
+                path.starts_with(pattern.as_str())
+            }
+        })
+}
+
+/// `*` matches any sequence of characters (including `/`), `?` matches one character.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    // Backtrack only to the last `*`, that is enough for this kind of patterns.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None; // the position after the last `*` and the text position it matched up to
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{cache_control::UpstreamFreshness, config::CacheConfig};
+
+    use super::{cache_ttl, glob_matches, response_ttl, upstream_ttl};
+
+    fn config(rules: &str) -> CacheConfig {
+        toml::from_str(&format!("cache_timeout = \"1d\"\n{rules}")).unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("/v1/*", "/v1/embeddings", true),
+            ("/v1/*", "/v2/embeddings", false),
+            ("/v1/*/items", "/v1/a/b/items", true),
+            ("/v1/*/items", "/v1/a/b/items/1", false),
+            ("/v?/chat", "/v1/chat", true),
+            ("/v?/chat", "/v10/chat", false),
+            ("*", "", true),
+            ("/a*b*c", "/aXbYbZc", true),
+            ("/a*b*c", "/aXbYbZ", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(glob_matches(pattern.as_bytes(), text.as_bytes()), *expected, "{pattern} {text}");
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = config(r#"
+            [[rules]]
+            host = "api.openai.com"
+            path = "/v1/embeddings"
+            ttl = "7d"
+
+            [[rules]]
+            host = "api.exchange.com"
+            path = "/rates/*"
+            method = "GET"
+            ttl = "10s"
+
+            [[rules]]
+            host = "api.exchange.com"
+            no_cache = true
+
+            [[rules]]
+            path = "/v1/models"
+        "#);
+        let day = Duration::from_secs(24 * 3600);
+        let cases: &[(&str, &str, &str, Option<Duration>)] = &[
+            ("POST", "api.openai.com", "/v1/embeddings", Some(7 * day)),
+            ("POST", "API.OpenAI.com", "/v1/embeddings?x=1", Some(7 * day)),
+            ("POST", "api.openai.com", "/v1/chat/completions", Some(day)),
+            ("get", "api.exchange.com", "/rates/usd", Some(Duration::from_secs(10))),
+            ("POST", "api.exchange.com", "/rates/usd", None),
+            ("GET", "api.exchange.com", "/other", None),
+            ("GET", "example.com", "/v1/models", Some(day)),
+        ];
+        for (method, host, path, expected) in cases {
+            assert_eq!(cache_ttl(&config, method, host, path), *expected, "{method} {host}{path}");
+        }
+    }
+
+    #[test]
+    fn test_response_ttl() {
+        let day = Duration::from_secs(24 * 3600);
+        let minute = Duration::from_secs(60);
+        let default = config("");
+        let custom = config(r#"
+            cacheable_statuses = ["2XX", 404, "429", "5xx"]
+            error_ttl = "1m"
+        "#);
+        let cases: &[(&CacheConfig, u16, Option<Duration>)] = &[
+            (&default, 200, Some(day)),
+            (&default, 204, Some(day)),
+            (&default, 301, None),
+            (&default, 404, Some(day)),
+            (&default, 429, None),
+            (&default, 500, None),
+            (&custom, 200, Some(day)),
+            (&custom, 404, Some(minute)),
+            (&custom, 429, Some(minute)),
+            (&custom, 503, Some(minute)),
+            (&custom, 403, None),
+        ];
+        for (config, status, expected) in cases {
+            assert_eq!(response_ttl(config, *status, day), *expected, "{status}");
+        }
+        assert_eq!(response_ttl(&custom, 500, Duration::from_secs(10)), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_upstream_ttl() {
+        let day = Duration::from_secs(24 * 3600);
+        let minute = Duration::from_secs(60);
+        let week = 7 * day;
+        let config = config(r#"
+            freshness_per_host = { "respect.com" = "respect", "min.com" = "respect-min", "ignore.com" = "ignore" }
+            freshness = "respect-min"
+        "#);
+        let cases: &[(&str, UpstreamFreshness, Option<Duration>)] = &[
+            ("ignore.com", UpstreamFreshness::NoStore, Some(day)),
+            ("ignore.com", UpstreamFreshness::MaxAge(minute), Some(day)),
+            ("respect.com", UpstreamFreshness::Unspecified, Some(day)),
+            ("respect.com", UpstreamFreshness::NoStore, None),
+            ("respect.com", UpstreamFreshness::MaxAge(minute), Some(minute)),
+            ("respect.com", UpstreamFreshness::MaxAge(week), Some(week)),
+            ("respect.com", UpstreamFreshness::MaxAge(Duration::ZERO), None),
+            ("min.com", UpstreamFreshness::MaxAge(minute), Some(minute)),
+            ("min.com", UpstreamFreshness::MaxAge(week), Some(day)),
+            ("min.com", UpstreamFreshness::NoStore, None),
+            ("other.com", UpstreamFreshness::MaxAge(week), Some(day)),
+        ];
+        for (host, upstream, expected) in cases {
+            assert_eq!(upstream_ttl(&config, host, *upstream, day), *expected, "{host} {upstream:?}");
+        }
+        assert_eq!(upstream_ttl(&self::config(""), "a.com", UpstreamFreshness::NoStore, day), Some(day));
+    }
+
+    #[test]
+    fn test_invalid_status_pattern() {
+        for patterns in [r#"["6xx"]"#, r#"["2x"]"#, "[99]", r#"["ok"]"#] {
+            let config = format!("cache_timeout = \"1d\"\ncacheable_statuses = {patterns}");
+            assert!(toml::from_str::<CacheConfig>(&config).is_err(), "{patterns}");
+        }
+    }
+}
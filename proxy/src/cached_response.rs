@@ -0,0 +1,370 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use bytes::Bytes;
+
+use crate::errors::{InvalidHeaderNameError, InvalidHeaderValueError, MyCorruptedDBError, MyResult};
+
+/// Starts every serialized response. The old text format starts with an ASCII digit instead.
+const MAGIC: &[u8] = b"\xffJP";
+const VERSION: u8 = 2;
+/// Responses without `FetchInfo`.
+const VERSION_1: u8 = 1;
+
+/// When and from where a response was fetched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchInfo {
+    /// Stored with millisecond precision.
+    pub fetched_at: SystemTime,
+    /// Time to receive the whole response from upstream, stored with microsecond precision.
+    pub latency: Duration,
+    pub upstream_host: String,
+    /// How long the response is kept in the cache, stored with millisecond precision.
+    pub ttl: Duration,
+    /// Set when upstream failed and this expired response was served instead.
+    /// Until then, it is served without asking upstream again. Stored with millisecond precision.
+    pub retry_after: Option<SystemTime>,
+}
+
+impl FetchInfo {
+    pub fn expires_at(&self) -> SystemTime {
+        self.fetched_at + self.ttl
+    }
+
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.fetched_at).unwrap_or_default()
+    }
+}
+
+/// An upstream response, as stored in the cache.
+///
+/// Format (version 2), all integers big endian:
+/// `MAGIC`, version byte, status (`u16`),
+/// `1` followed by fetch time (`u64` milliseconds since UNIX epoch), latency (`u64` microseconds),
+/// TTL (`u64` milliseconds), upstream host length (`u32`), upstream host; or `0` if there is no `FetchInfo`,
+/// or `2` followed by the same and retry time (`u64` milliseconds since UNIX epoch) if `retry_after` is set,
+/// number of headers (`u32`),
+/// for every header: name length (`u32`), name, value length (`u32`), value,
+/// then the body to the end of data.
+/// Version 1 is the same without the `FetchInfo` part.
+///
+/// The body shares memory with the serialized data, so a cache hit doesn't copy it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub status: u16,
+    /// Header names and values are kept as raw bytes, they need not be UTF-8.
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Bytes,
+    /// Missing in entries stored by older versions.
+    pub fetch_info: Option<FetchInfo>,
+}
+
+impl CachedResponse {
+    pub async fn from_reqwest(response: reqwest::Response) -> MyResult<Self> {
+        let status = response.status().as_u16();
+        let headers = response.headers().iter()
+            .map(|(k, v)| (k.as_str().as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect();
+        let body = response.bytes().await?;
+        Ok(Self { status, headers, body, fetch_info: None })
+    }
+
+    /// A failed request, as `MyError` responds to it.
+    pub fn failure(status: u16, message: String) -> Self {
+        Self {
+            status,
+            headers: vec![(b"content-type".to_vec(), b"text/plain; charset=utf-8".to_vec())],
+            body: message.into(),
+            fetch_info: None,
+        }
+    }
+
+    /// The first value of the header `name`, given in lowercase.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Whether upstream can tell if the response changed, see `cache_control::conditional_headers`.
+    pub fn has_validators(&self) -> bool {
+        self.header("etag").is_some() || self.header("last-modified").is_some()
+    }
+
+    /// Replaces the stored headers with those of a `304 Not Modified` response to a conditional request.
+    pub fn update_headers(&mut self, headers: &http::HeaderMap) {
+        for name in headers.keys() {
+            // They describe the empty body of the 304 response.
+            if name == http::header::CONTENT_LENGTH || name == http::header::TRANSFER_ENCODING {
+                continue;
+            }
+            self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name.as_str().as_bytes()));
+            self.headers.extend(
+                headers.get_all(name).iter().map(|v| (name.as_str().as_bytes().to_vec(), v.as_bytes().to_vec())));
+        }
+    }
+
+    pub fn serialize(&self) -> Bytes {
+        let headers_len: usize = self.headers.iter().map(|(k, v)| 8 + k.len() + v.len()).sum();
+        let fetch_info_len = self.fetch_info.as_ref().map_or(0, |info| 36 + info.upstream_host.len());
+        let mut data = Vec::with_capacity(MAGIC.len() + 8 + fetch_info_len + headers_len + self.body.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&self.status.to_be_bytes());
+        if let Some(info) = &self.fetch_info {
+            let fetched_at = info.fetched_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            data.push(if info.retry_after.is_some() { 2 } else { 1 });
+            data.extend_from_slice(&(fetched_at.as_millis() as u64).to_be_bytes());
+            data.extend_from_slice(&(info.latency.as_micros() as u64).to_be_bytes());
+            data.extend_from_slice(&(info.ttl.as_millis() as u64).to_be_bytes());
+            data.extend_from_slice(&(info.upstream_host.len() as u32).to_be_bytes());
+            data.extend_from_slice(info.upstream_host.as_bytes());
+            if let Some(retry_after) = info.retry_after {
+                let retry_after = retry_after.duration_since(UNIX_EPOCH).unwrap_or_default();
+                data.extend_from_slice(&(retry_after.as_millis() as u64).to_be_bytes());
+            }
+        } else {
+            data.push(0);
+        }
+        data.extend_from_slice(&(self.headers.len() as u32).to_be_bytes());
+        for (k, v) in &self.headers {
+            data.extend_from_slice(&(k.len() as u32).to_be_bytes());
+            data.extend_from_slice(k);
+            data.extend_from_slice(&(v.len() as u32).to_be_bytes());
+            data.extend_from_slice(v);
+        }
+        data.extend_from_slice(&self.body);
+        data.into()
+    }
+
+    /// Reads both the current and the old (`status\nname\tvalue\r...\nbody`) format,
+    /// so that entries cached by older versions remain usable.
+    pub fn deserialize(data: &Bytes) -> MyResult<Self> {
+        match data.strip_prefix(MAGIC) {
+            Some([version @ (VERSION_1 | VERSION), ..]) => Self::deserialize_binary(data, *version),
+            Some(_) => Err(MyCorruptedDBError::default().into()),
+            None => Self::deserialize_legacy(data),
+        }
+    }
+
+    fn deserialize_binary(data: &Bytes, version: u8) -> MyResult<Self> {
+        let mut reader = Reader { data: &data[MAGIC.len() + 1..] };
+        let status = u16::from_be_bytes(reader.take_array()?);
+        let fetch_info = if version == VERSION_1 {
+            None
+        } else {
+            match reader.take_array()? {
+                [0] => None,
+                [flag @ (1 | 2)] => Some(Self::read_fetch_info(&mut reader, flag == 2)?),
+                _ => return Err(MyCorruptedDBError::default().into()),
+            }
+        };
+        let headers_count = u32::from_be_bytes(reader.take_array()?);
+        let mut headers = Vec::new();
+        for _ in 0..headers_count {
+            let k = reader.take_prefixed()?.to_vec();
+            let v = reader.take_prefixed()?.to_vec();
+            headers.push((k, v));
+        }
+        let body = data.slice(data.len() - reader.data.len()..);
+        Ok(Self { status, headers, body, fetch_info })
+    }
+
+    fn read_fetch_info(reader: &mut Reader, has_retry_after: bool) -> MyResult<FetchInfo> {
+        let fetched_at = Duration::from_millis(u64::from_be_bytes(reader.take_array()?));
+        let latency = Duration::from_micros(u64::from_be_bytes(reader.take_array()?));
+        let ttl = Duration::from_millis(u64::from_be_bytes(reader.take_array()?));
+        let upstream_host = String::from_utf8(reader.take_prefixed()?.to_vec())
+            .map_err(|_| MyCorruptedDBError::default())?;
+        let retry_after = if has_retry_after {
+            let retry_after = Duration::from_millis(u64::from_be_bytes(reader.take_array()?));
+            Some(UNIX_EPOCH.checked_add(retry_after).ok_or_else(MyCorruptedDBError::default)?)
+        } else {
+            None
+        };
+        // Checked here, so that `FetchInfo::expires_at` can't overflow.
+        let fetched_at = UNIX_EPOCH.checked_add(fetched_at)
+            .filter(|fetched_at| fetched_at.checked_add(ttl).is_some())
+            .ok_or_else(MyCorruptedDBError::default)?;
+        Ok(FetchInfo { fetched_at, latency, upstream_host, ttl, retry_after })
+    }
+
+    fn deserialize_legacy(data: &Bytes) -> MyResult<Self> {
+        let mut iter1 = data.splitn(3, |&c| c == b'\n');
+        let status_code_bytes = iter1.next().ok_or_else(MyCorruptedDBError::default)?;
+        let headers_bytes = iter1.next().ok_or_else(MyCorruptedDBError::default)?;
+        let body = iter1.next().ok_or_else(MyCorruptedDBError::default)?;
+
+        let status = std::str::from_utf8(status_code_bytes).ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(MyCorruptedDBError::default)?;
+        let mut headers = Vec::new();
+        if !headers_bytes.is_empty() {
+            for header_str in headers_bytes.split(|&c| c == b'\r') {
+                let mut iter2 = header_str.splitn(2, |&c| c == b'\t');
+                let k = iter2.next().ok_or_else(MyCorruptedDBError::default)?;
+                let v = iter2.next().ok_or_else(MyCorruptedDBError::default)?;
+                headers.push((k.to_vec(), v.to_vec()));
+            }
+        }
+        let body = data.slice_ref(body);
+        Ok(Self { status, headers, body, fetch_info: None })
+    }
+
+    pub fn into_http_response(self) -> MyResult<actix_web::HttpResponse<Bytes>> {
+        let mut response = actix_web::HttpResponse::with_body(StatusCode::from_u16(self.status)?, self.body);
+        let headers = response.headers_mut();
+        for (k, v) in self.headers {
+            headers.append(
+                http_for_actix::HeaderName::from_bytes(&k).map_err(|_| InvalidHeaderNameError::default())?,
+                http_for_actix::HeaderValue::from_bytes(&v).map_err(|_| InvalidHeaderValueError::default())?,
+            );
+        }
+        Ok(response)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> MyResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(MyCorruptedDBError::default().into());
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> MyResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn take_prefixed(&mut self) -> MyResult<&'a [u8]> {
+        let len = u32::from_be_bytes(self.take_array()?);
+        self.take(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use bytes::Bytes;
+    use proptest::prelude::*;
+
+    use super::{CachedResponse, FetchInfo};
+
+    fn fetch_info() -> impl Strategy<Value = FetchInfo> {
+        (0..1u64 << 42, any::<u64>(), 0..1u64 << 40, any::<String>(), prop::option::of(0..1u64 << 42))
+            .prop_map(|(fetched_at, latency, ttl, upstream_host, retry_after)| FetchInfo {
+                fetched_at: UNIX_EPOCH + Duration::from_millis(fetched_at),
+                latency: Duration::from_micros(latency),
+                upstream_host,
+                ttl: Duration::from_millis(ttl),
+                retry_after: retry_after.map(|retry_after| UNIX_EPOCH + Duration::from_millis(retry_after)),
+            })
+    }
+
+    fn cached_response() -> impl Strategy<Value = CachedResponse> {
+        (
+            any::<u16>(),
+            prop::collection::vec((any::<Vec<u8>>(), any::<Vec<u8>>()), 0..8),
+            any::<Vec<u8>>(),
+            prop::option::of(fetch_info()),
+        ).prop_map(|(status, headers, body, fetch_info)| CachedResponse { status, headers, body: body.into(), fetch_info })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(response in cached_response()) {
+            let data = response.serialize();
+            prop_assert_eq!(CachedResponse::deserialize(&data).unwrap(), response);
+        }
+
+        #[test]
+        fn test_truncated_data_is_an_error(response in cached_response(), cut in any::<prop::sample::Index>()) {
+            let data = response.serialize();
+            let header_part = data.len() - response.body.len();
+            let cut = cut.index(header_part);
+            prop_assert!(CachedResponse::deserialize(&data.slice(..cut)).is_err());
+        }
+
+        #[test]
+        fn test_arbitrary_data_does_not_panic(data in any::<Vec<u8>>()) {
+            let _ = CachedResponse::deserialize(&data.into());
+        }
+    }
+
+    #[test]
+    fn test_legacy_format() {
+        let data = Bytes::from_static(b"200\ncontent-type\ttext/plain\rx-test\ta\nbody\nwith newlines");
+        assert_eq!(CachedResponse::deserialize(&data).unwrap(), CachedResponse {
+            status: 200,
+            headers: vec![
+                (b"content-type".to_vec(), b"text/plain".to_vec()),
+                (b"x-test".to_vec(), b"a".to_vec()),
+            ],
+            body: Bytes::from_static(b"body\nwith newlines"),
+            fetch_info: None,
+        });
+    }
+
+    #[test]
+    fn test_legacy_format_without_headers() {
+        let response = CachedResponse::deserialize(&Bytes::from_static(b"404\n\n")).unwrap();
+        assert_eq!(response.status, 404);
+        assert!(response.headers.is_empty());
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn test_header_with_control_characters() {
+        let response = CachedResponse {
+            status: 200,
+            headers: vec![(b"x-test".to_vec(), b"a\tb\r\n\xff".to_vec())],
+            body: Bytes::new(),
+            fetch_info: None,
+        };
+        assert_eq!(CachedResponse::deserialize(&response.serialize()).unwrap(), response);
+    }
+
+    #[test]
+    fn test_version_1() {
+        let data = Bytes::from_static(b"\xffJP\x01\x00\xc8\x00\x00\x00\x01\x00\x00\x00\x01a\x00\x00\x00\x01bbody");
+        assert_eq!(CachedResponse::deserialize(&data).unwrap(), CachedResponse {
+            status: 200,
+            headers: vec![(b"a".to_vec(), b"b".to_vec())],
+            body: Bytes::from_static(b"body"),
+            fetch_info: None,
+        });
+    }
+
+    #[test]
+    fn test_update_headers() {
+        let mut response = CachedResponse {
+            status: 200,
+            headers: vec![
+                (b"ETag".to_vec(), b"\"1\"".to_vec()),
+                (b"content-length".to_vec(), b"4".to_vec()),
+                (b"vary".to_vec(), b"a".to_vec()),
+                (b"vary".to_vec(), b"b".to_vec()),
+                (b"x-kept".to_vec(), b"yes".to_vec()),
+            ],
+            body: Bytes::from_static(b"body"),
+            fetch_info: None,
+        };
+        assert!(response.has_validators());
+        let not_modified: http::HeaderMap = [("etag", "\"2\""), ("content-length", "0"), ("vary", "c")].into_iter()
+            .map(|(k, v)| (http::HeaderName::from_static(k), http::HeaderValue::from_static(v)))
+            .collect();
+        response.update_headers(&not_modified);
+        assert_eq!(response.header("etag"), Some(b"\"2\"".as_slice()));
+        assert_eq!(response.header("content-length"), Some(b"4".as_slice()));
+        assert_eq!(response.headers.iter().filter(|(k, _)| k == b"vary").count(), 1);
+        assert_eq!(response.header("x-kept"), Some(b"yes".as_slice()));
+        assert!(!CachedResponse::failure(500, String::new()).has_validators());
+    }
+}
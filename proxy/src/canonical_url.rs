@@ -0,0 +1,110 @@
+use crate::config::UrlCanonicalization;
+
+/// Brings a path with a query to a canonical form, so that equivalent URLs share the cache.
+pub fn canonicalize(path_and_query: &str, config: &UrlCanonicalization) -> String {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+    // `https://example.com` is the same as `https://example.com/`.
+    let mut result = if path.is_empty() { "/".to_string() } else { normalize_escapes(path, config) };
+
+    let Some(query) = query else {
+        return result;
+    };
+    let mut params = query.split('&')
+        .map(|param| normalize_escapes(param, config))
+        .filter(|param| !config.drop_params.iter().any(|dropped| dropped == param_name(param)))
+        .collect::<Vec<_>>();
+    if config.sort_query {
+        // Stable, to keep the order of repeated parameters.
+        params.sort_by(|a, b| param_name(a).cmp(param_name(b)));
+    }
+    let query = params.join("&");
+    if !(query.is_empty() && config.drop_empty_query) {
+        result.push('?');
+        result.push_str(&query);
+    }
+    result
+}
+
+fn param_name(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(name, _)| name)
+}
+
+/// Decodes percent-encoded unreserved characters (RFC 3986, section 2.3)
+/// and uppercases the hex digits of the other escapes.
+fn normalize_escapes(s: &str, config: &UrlCanonicalization) -> String {
+    if !config.decode_unreserved {
+        return s.to_string();
+    }
+    let bytes = s.as_bytes();
+    let mut result = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // `from_str_radix` alone would take a sign, such as in `%+f`.
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(c)) if c.is_ascii_alphanumeric() || b"-._~".contains(&c) => result.push(c as char),
+            (b'%', Some(c)) => result.push_str(&format!("%{c:02X}")),
+            _ => {
+                // Not an escape, copy the whole (maybe multibyte) character.
+                let len = s[i..].chars().next().map_or(1, char::len_utf8);
+                result.push_str(&s[i..i + len]);
+                i += len;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::UrlCanonicalization;
+
+    use super::canonicalize;
+
+    #[test]
+    fn test_canonicalize() {
+        let decoded = UrlCanonicalization {
+            decode_unreserved: true,
+            drop_empty_query: true,
+            ..UrlCanonicalization::default()
+        };
+        let none = UrlCanonicalization::default();
+        let sorted = UrlCanonicalization {
+            sort_query: true,
+            drop_params: vec!["_".to_string(), "cb".to_string()],
+            ..decoded.clone()
+        };
+        let cases: &[(&UrlCanonicalization, &str, &str)] = &[
+            (&decoded, "", "/"),
+            (&decoded, "/", "/"),
+            (&decoded, "/xx?", "/xx"),
+            (&decoded, "?", "/"),
+            (&decoded, "/xx?a=1", "/xx?a=1"),
+            (&decoded, "/%7Euser/%61%2Db", "/~user/a-b"),
+            (&decoded, "/a%2fb?q=%e2%82%ac", "/a%2Fb?q=%E2%82%AC"),
+            (&decoded, "/a%2?b=%zz", "/a%2?b=%zz"),
+            (&decoded, "/a%+f?b=%-1", "/a%+f?b=%-1"),
+            (&decoded, "/€?x=ü", "/€?x=ü"),
+            (&decoded, "/?b=2&a=1", "/?b=2&a=1"),
+            (&none, "/xx?", "/xx?"),
+            (&none, "/%7Euser", "/%7Euser"),
+            (&none, "", "/"),
+            (&sorted, "/?b=2&a=1&b=1", "/?a=1&b=2&b=1"),
+            (&sorted, "/?cb=123&q=x&_=456", "/?q=x"),
+            (&sorted, "/?cb=123", "/"),
+            (&sorted, "/?c%62=123&q", "/?q"),
+            (&sorted, "/?cbx=1", "/?cbx=1"),
+        ];
+        for (config, url, expected) in cases {
+            assert_eq!(canonicalize(url, config), *expected, "{url}");
+        }
+    }
+}
@@ -50,12 +50,266 @@ pub struct ResponseHeaders {
     pub show_hit_miss: bool,
     #[serde(default="default_add_forwarded_from_header")]
     pub add_forwarded_from_header: bool,
+    /// Add the standard `Age` header: seconds since the response was fetched from upstream.
+    #[serde(default="default_show_age")]
+    pub show_age: bool,
+    /// Add `X-JoinProxy-Cached-At` and `X-JoinProxy-Expires-At` headers (HTTP dates).
+    #[serde(default="default_show_cache_times")]
+    pub show_cache_times: bool,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    Memory,
+    Redis,
+    Memcached,
+    Disk,
+    Tiered,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    Lru,
+    Lfu,
+}
+
+/// Whether upstream `Cache-Control` and `Expires` headers are followed.
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FreshnessPolicy {
+    /// Cache for our TTL only.
+    #[default]
+    Ignore,
+    /// Cache for the upstream TTL, if given.
+    Respect,
+    /// Cache for the shorter of the upstream TTL and ours.
+    RespectMin,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RedisCacheConfig {
+    pub url: String,
+    #[serde(default="default_key_prefix")]
+    pub key_prefix: String,
+    /// How long a "fetch in progress" lock lives if its holder dies.
+    #[serde(default="default_lock_timeout", deserialize_with = "parse_duration")]
+    pub lock_timeout: Duration,
+    /// How often other instances check whether the lock was released.
+    #[serde(default="default_lock_poll_interval", deserialize_with = "parse_duration")]
+    pub lock_poll_interval: Duration,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct MemcachedCacheConfig {
+    /// `host:port` of the memcached server.
+    pub address: String,
+    #[serde(default="default_key_prefix")]
+    pub key_prefix: String,
+    /// How long a "fetch in progress" lock lives if its holder dies.
+    /// Short, as a dead holder blocks the key till then, but upstream requests that take longer
+    /// may be sent by several instances.
+    #[serde(default="default_memcached_lock_timeout", deserialize_with = "parse_duration")]
+    pub lock_timeout: Duration,
+    /// How often other instances check whether the lock was released.
+    #[serde(default="default_lock_poll_interval", deserialize_with = "parse_duration")]
+    pub lock_poll_interval: Duration,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct DiskCacheConfig {
+    /// Directory of the embedded database.
+    pub path: String,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct TieredCacheConfig {
+    /// The slower backend behind the in-memory L1 cache.
+    pub l2: CacheBackend,
+    /// How long responses are kept in L1, `cache_timeout` by default.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub l1_cache_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct CompressionConfig {
+    /// zstd compression level.
+    #[serde(default="default_compression_level")]
+    pub level: i32,
+    /// Smaller values are stored uncompressed.
+    #[serde(default="default_compression_min_size")]
+    pub min_size: usize,
+    /// How often the bytes saved by compression are logged.
+    #[serde(default="default_compression_stats_interval", deserialize_with = "parse_duration")]
+    pub stats_interval: Duration,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct EncryptionConfig {
+    /// File with a base64 encoded 256-bit key to encrypt new entries.
+    pub key_file: String,
+    /// Keys used before rotation, to read older entries.
+    #[serde(default)]
+    pub previous_key_files: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RefreshAheadConfig {
+    /// Part of the TTL after which a frequently used entry is refreshed in background.
+    pub threshold: f64,
+    /// Hits after `threshold` that make an entry frequently used.
+    #[serde(default="default_refresh_ahead_min_hits")]
+    pub min_hits: u32,
+}
+
+/// Which request headers are a part of the cache key. Header names are case insensitive.
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct CacheKeyConfig {
+    /// If set, only these headers, `Host`, and the request directive headers are a part of the key.
+    pub include_headers: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude_headers: Vec<String>,
+    /// Replaces `include_headers` for a host.
+    #[serde(default)]
+    pub include_headers_per_host: HashMap<String, Vec<String>>,
+    /// Excluded for a host in addition to `exclude_headers`.
+    #[serde(default)]
+    pub exclude_headers_per_host: HashMap<String, Vec<String>>,
+    /// Applies to the URL both in the key and sent upstream.
+    #[serde(default)]
+    pub canonical_url: UrlCanonicalization,
+    /// Replaces `canonical_url` for a host.
+    #[serde(default)]
+    pub canonical_url_per_host: HashMap<String, UrlCanonicalization>,
+    /// Hash JSON bodies in the canonical form of RFC 8785, so that key order and whitespace don't matter.
+    /// The body is sent upstream as is.
+    #[serde(default)]
+    pub canonical_json: bool,
+    #[serde(default)]
+    pub canonical_json_per_host: HashMap<String, bool>,
+}
+
+/// Every step is off by default, as it changes the URL sent upstream.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(default)]
+pub struct UrlCanonicalization {
+    /// Decode percent-encoded unreserved characters, such as `%7E` for `~`.
+    pub decode_unreserved: bool,
+    /// Drop `?` without parameters.
+    pub drop_empty_query: bool,
+    /// Sort query parameters by name.
+    pub sort_query: bool,
+    /// Drop these query parameters, such as cache-busters.
+    pub drop_params: Vec<String>,
+}
+
+/// A status code ("404") or a class of them ("2xx").
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusPattern {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl StatusPattern {
+    pub fn matches(&self, status: u16) -> bool {
+        (self.min..=self.max).contains(&status)
+    }
+}
+
+impl std::str::FromStr for StatusPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid status code pattern: {s}");
+        match s.to_ascii_lowercase().as_bytes() {
+            [class @ b'1'..=b'5', b'x', b'x'] => {
+                let min = (class - b'0') as u16 * 100;
+                Ok(Self { min, max: min + 99 })
+            }
+            _ => {
+                let status: u16 = s.parse().map_err(|_| invalid())?;
+                if !(100..=999).contains(&status) {
+                    return Err(invalid());
+                }
+                Ok(Self { min: status, max: status })
+            }
+        }
+    }
+}
+
+/// The first rule matching a request decides how long its response is cached.
+/// Omitted `host`, `path`, and `method` match anything.
+#[derive(Clone, Deserialize, Debug)]
+pub struct CacheRule {
+    pub host: Option<String>,
+    /// A path prefix, or a glob pattern (`*` matches any characters, `?` one character) if it contains `*` or `?`.
+    pub path: Option<String>,
+    pub method: Option<String>,
+    /// `cache_timeout` by default.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub ttl: Option<Duration>,
+    /// Never cache matching requests.
+    #[serde(default)]
+    pub no_cache: bool,
 }
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct CacheConfig {
     #[serde(deserialize_with = "parse_duration")]
     pub cache_timeout: Duration,
+    #[serde(default="default_cache_backend")]
+    pub backend: CacheBackend,
+    /// How often expired entries are removed from the in-memory and disk caches.
+    #[serde(default="default_reaper_interval", deserialize_with = "parse_duration")]
+    pub reaper_interval: Duration,
+    /// Limits of the in-memory cache.
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+    #[serde(default="default_eviction_policy")]
+    pub eviction_policy: EvictionPolicy,
+    #[serde(default)]
+    pub rules: Vec<CacheRule>,
+    #[serde(default)]
+    pub key: CacheKeyConfig,
+    /// Responses with other statuses are not cached.
+    #[serde(default="default_cacheable_statuses", deserialize_with = "parse_status_patterns")]
+    pub cacheable_statuses: Vec<StatusPattern>,
+    /// TTL of cacheable 4xx and 5xx responses, if shorter than the usual one.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub error_ttl: Option<Duration>,
+    /// How long a failed upstream request (an error, or a non-cacheable 429 or 5xx) is served to identical requests,
+    /// instead of each of them going upstream. Zero disables it.
+    #[serde(default="default_failure_ttl", deserialize_with = "parse_duration")]
+    pub failure_ttl: Duration,
+    /// The longest TTL a canister can set with `X-JoinProxy-Cache-TTL`, `cache_timeout` by default.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub max_directive_ttl: Option<Duration>,
+    /// How long after expiration a response is still served, while it is refreshed in background.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub stale_while_revalidate: Option<Duration>,
+    /// How long after expiration a response is still served, if upstream fails.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub stale_if_error: Option<Duration>,
+    /// How long after expiration a response with `ETag` or `Last-Modified` is kept,
+    /// to ask upstream whether it changed instead of downloading it again.
+    #[serde(default, deserialize_with = "parse_duration_option")]
+    pub revalidation_period: Option<Duration>,
+    pub refresh_ahead: Option<RefreshAheadConfig>,
+    #[serde(default)]
+    pub freshness: FreshnessPolicy,
+    #[serde(default)]
+    pub freshness_per_host: HashMap<String, FreshnessPolicy>,
+    pub redis: Option<RedisCacheConfig>,
+    pub memcached: Option<MemcachedCacheConfig>,
+    pub disk: Option<DiskCacheConfig>,
+    pub tiered: Option<TieredCacheConfig>,
+    pub compression: Option<CompressionConfig>,
+    pub encryption: Option<EncryptionConfig>,
+    /// File to keep the in-memory cache (of the memory or tiered backend) in between restarts.
+    pub snapshot_file: Option<String>,
+    #[serde(default="default_snapshot_interval", deserialize_with = "parse_duration")]
+    pub snapshot_interval: Duration,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -113,6 +367,14 @@ fn default_show_hit_miss() -> bool {
     false
 }
 
+fn default_show_age() -> bool {
+    false
+}
+
+fn default_show_cache_times() -> bool {
+    false
+}
+
 fn default_upstream_connect_timeout() -> Option<Duration> {
     Some(Duration::from_secs(10))
 }
@@ -133,25 +395,89 @@ fn default_ic_local() -> bool {
     false
 }
 
-fn extract_duration<'de, D>(s: &str) -> Result<Duration, D::Error>
-where
-    D: Deserializer<'de>,
-{
+fn default_cache_backend() -> CacheBackend {
+    CacheBackend::Memory
+}
+
+fn default_eviction_policy() -> EvictionPolicy {
+    EvictionPolicy::Lru
+}
+
+fn default_reaper_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_snapshot_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_cacheable_statuses() -> Vec<StatusPattern> {
+    vec![StatusPattern { min: 200, max: 299 }, StatusPattern { min: 404, max: 404 }]
+}
+
+fn default_failure_ttl() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_refresh_ahead_min_hits() -> u32 {
+    2
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+fn default_compression_stats_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_key_prefix() -> String {
+    "join-proxy:".to_string()
+}
+
+fn default_lock_timeout() -> Duration {
+    Duration::from_secs(180) // should exceed the upstream total timeout
+}
+
+fn default_memcached_lock_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_lock_poll_interval() -> Duration {
+    Duration::from_millis(50)
+}
+
+/// Parses a duration such as "100ms", "10s", "5m", "2h", or "7d".
+pub fn parse_duration_str(s: &str) -> Result<Duration, String> {
     let pos = s.find(|c: char| !c.is_numeric()).unwrap_or(s.len());
     let (value_str, unit) = s.split_at(pos);
 
-    let value: u64 = value_str.parse().map_err(serde::de::Error::custom)?;
+    let value: u64 = value_str.parse().map_err(|e| format!("{e}"))?;
+    let secs = |unit_secs: u64| value.checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "Duration is too long".to_string());
 
     match unit {
-        "d" => Ok(Duration::from_secs(value*3600*24)),
-        "h" => Ok(Duration::from_secs(value*3600)),
-        "m" => Ok(Duration::from_secs(value*60)),
-        "s" => Ok(Duration::from_secs(value)),
+        "d" => secs(3600*24),
+        "h" => secs(3600),
+        "m" => secs(60),
+        "s" => secs(1),
         "ms" => Ok(Duration::from_millis(value)),
-        _ => Err(serde::de::Error::custom("Invalid duration unit")),
+        _ => Err("Invalid duration unit".to_string()),
     }
 }
 
+fn extract_duration<'de, D>(s: &str) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_duration_str(s).map_err(serde::de::Error::custom)
+}
+
 fn parse_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -172,6 +498,27 @@ where
     })
 }
 
+fn parse_status_patterns<'de, D>(deserializer: D) -> Result<Vec<StatusPattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Pattern {
+        Status(u16),
+        Text(String),
+    }
+
+    let patterns: Vec<Pattern> = serde::Deserialize::deserialize(deserializer)?;
+    patterns.into_iter()
+        .map(|pattern| match pattern {
+            Pattern::Status(status) => status.to_string().parse(),
+            Pattern::Text(s) => s.parse(),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
 fn deserialize_canister_id<'de, D>(deserializer: D) -> Result<Principal, D::Error>
 where
     D: Deserializer<'de>,
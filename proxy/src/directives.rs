@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+
+use crate::{config::parse_duration_str, errors::{InvalidDirectiveError, MyResult}};
+
+pub const CACHE_TTL_HEADER: &str = "x-joinproxy-cache-ttl";
+pub const CACHE_HEADER: &str = "x-joinproxy-cache";
+pub const TIMEOUT_HEADER: &str = "x-joinproxy-timeout";
+
+/// These headers are for the proxy only and aren't sent upstream.
+/// They remain in the request hash, so that the same outcall from all replicas is still coalesced.
+pub const DIRECTIVE_HEADERS: [&str; 3] = [CACHE_TTL_HEADER, CACHE_HEADER, TIMEOUT_HEADER];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    #[default]
+    Normal,
+    /// Neither read nor store the cache.
+    Bypass,
+    /// Fetch from upstream even if cached, and store the new response.
+    Refresh,
+    /// Never go upstream, answer 504 Gateway Timeout if not cached.
+    OnlyIfCached,
+}
+
+/// Cache directives of a request, set by the calling canister.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestDirectives {
+    /// Overrides the TTL of cache rules, up to a configured maximum.
+    pub ttl: Option<Duration>,
+    pub cache: CacheMode,
+    /// Upstream deadline, no longer than `upstream_timeouts.total_timeout`.
+    pub timeout: Option<Duration>,
+}
+
+impl RequestDirectives {
+    /// A longer TTL than `max_ttl` is reduced to it.
+    pub fn from_headers(headers: &HeaderMap, max_ttl: Duration) -> MyResult<Self> {
+        let cache = match header_str(headers, CACHE_HEADER)? {
+            None => CacheMode::Normal,
+            Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "bypass" => CacheMode::Bypass,
+                "refresh" => CacheMode::Refresh,
+                "only-if-cached" => CacheMode::OnlyIfCached,
+                _ => return Err(InvalidDirectiveError("X-JoinProxy-Cache").into()),
+            },
+        };
+        Ok(Self {
+            ttl: header_duration(headers, CACHE_TTL_HEADER, "X-JoinProxy-Cache-TTL")?.map(|ttl| ttl.min(max_ttl)),
+            cache,
+            timeout: header_duration(headers, TIMEOUT_HEADER, "X-JoinProxy-Timeout")?,
+        })
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &'static str) -> MyResult<Option<&'a str>> {
+    Ok(headers.get(name).map(|value| value.to_str()).transpose()?)
+}
+
+/// A duration in the config format ("10s", "5m", ...) or a number of seconds.
+fn header_duration(headers: &HeaderMap, name: &'static str, display_name: &'static str) -> MyResult<Option<Duration>> {
+    let Some(value) = header_str(headers, name)? else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    let duration = match value.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => parse_duration_str(value),
+    };
+    Ok(Some(duration.map_err(|_| InvalidDirectiveError(display_name))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::{CacheMode, RequestDirectives};
+
+    type Headers = &'static [(&'static str, &'static str)];
+
+    fn directives(headers: Headers) -> Option<RequestDirectives> {
+        let mut map = HeaderMap::new();
+        for (k, v) in headers {
+            map.append(HeaderName::from_static(k), HeaderValue::from_static(v));
+        }
+        RequestDirectives::from_headers(&map, Duration::from_secs(3600)).ok()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cases: &[(Headers, Option<RequestDirectives>)] = &[
+            (&[], Some(RequestDirectives::default())),
+            (&[("x-joinproxy-cache-ttl", "30")], Some(RequestDirectives { ttl: Some(Duration::from_secs(30)), ..Default::default() })),
+            (&[("x-joinproxy-cache-ttl", "5m")], Some(RequestDirectives { ttl: Some(Duration::from_secs(300)), ..Default::default() })),
+            (&[("x-joinproxy-cache-ttl", "7d")], Some(RequestDirectives { ttl: Some(Duration::from_secs(3600)), ..Default::default() })),
+            (&[("x-joinproxy-cache-ttl", "18446744073709551615")], Some(RequestDirectives { ttl: Some(Duration::from_secs(3600)), ..Default::default() })),
+            (&[("x-joinproxy-cache-ttl", "18446744073709551615d")], None),
+            (&[("x-joinproxy-cache-ttl", "18446744073709551616")], None),
+            (&[("x-joinproxy-cache", "Bypass")], Some(RequestDirectives { cache: CacheMode::Bypass, ..Default::default() })),
+            (&[("x-joinproxy-cache", "refresh")], Some(RequestDirectives { cache: CacheMode::Refresh, ..Default::default() })),
+            (&[("x-joinproxy-cache", "only-if-cached")], Some(RequestDirectives { cache: CacheMode::OnlyIfCached, ..Default::default() })),
+            (&[("x-joinproxy-timeout", "500ms")], Some(RequestDirectives { timeout: Some(Duration::from_millis(500)), ..Default::default() })),
+            (&[("x-joinproxy-cache", "sometimes")], None),
+            (&[("x-joinproxy-cache-ttl", "-1")], None),
+            (&[("x-joinproxy-timeout", "1w")], None),
+        ];
+        for (headers, expected) in cases {
+            assert_eq!(&directives(headers), expected, "{headers:?}");
+        }
+    }
+}
@@ -14,7 +14,8 @@ pub enum MyError {
     #[error("Invalid HTTP method")]
     InvalidMethod(http::method::InvalidMethod),
     #[error("Invalid HTTP response")]
-    HttpResponse(reqwest::Response),
+    #[from(ignore)]
+    HttpResponse(Box<reqwest::Response>),
     #[error("Request error: {0}")]
     ReqwestError(reqwest::Error),
     #[error("Invalid HTTP status code")]
@@ -35,9 +36,22 @@ pub enum MyError {
     #[error("Candid error: {0}")]
     Candid(candid::Error),
     #[error("IC agent error: {0}")]
-    Agent(AgentError),
+    #[from(ignore)]
+    Agent(Box<AgentError>),
     #[error("Invalid URI: {0}")]
     InvalidUri(http::uri::InvalidUri),
+    #[error("Redis error: {0}")]
+    Redis(redis::RedisError),
+    #[error("Cache snapshot error: {0}")]
+    Bincode(bincode::Error),
+    #[error("Disk cache error: {0}")]
+    Sled(sled::Error),
+    #[error("{0}")]
+    Memcached(MemcachedError),
+    #[error("{0}")]
+    Unsupported(UnsupportedError),
+    #[error("{0}")]
+    InvalidDirective(InvalidDirectiveError),
 }
 
 #[derive(Debug, Default, Error)]
@@ -49,6 +63,34 @@ impl Display for MyCorruptedDBError {
     }
 }
 
+#[derive(Debug, Error)]
+pub struct MemcachedError(pub String);
+
+impl Display for MemcachedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Memcached error: {}", self.0)
+    }
+}
+
+#[derive(Debug, Error)]
+pub struct UnsupportedError(pub &'static str);
+
+impl Display for UnsupportedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` is not supported by this cache backend.", self.0)
+    }
+}
+
+/// A malformed `X-JoinProxy-*` request header, by name.
+#[derive(Debug, Error)]
+pub struct InvalidDirectiveError(pub &'static str);
+
+impl Display for InvalidDirectiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid {} header.", self.0)
+    }
+}
+
 #[derive(Debug, Default, Error)]
 pub struct InvalidHeaderNameError {}
 
@@ -73,9 +115,28 @@ impl From<anyhow::Error> for MyError {
     }
 }
 
+// Large errors are boxed, to keep `MyResult` small.
+impl From<reqwest::Response> for MyError {
+    fn from(response: reqwest::Response) -> Self {
+        Self::HttpResponse(Box::new(response))
+    }
+}
+
+impl From<AgentError> for MyError {
+    fn from(err: AgentError) -> Self {
+        Self::Agent(Box::new(err))
+    }
+}
+
 impl ResponseError for MyError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+        match self {
+            // Upstream is at fault, not the client.
+            MyError::ReqwestError(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            MyError::ReqwestError(e) if e.is_connect() || e.is_request() || e.is_body() || e.is_decode() =>
+                StatusCode::BAD_GATEWAY,
+            _ => StatusCode::BAD_REQUEST,
+        }
     }
     fn error_response(&self) -> HttpResponse {
         HttpResponse::build(self.status_code())
@@ -0,0 +1,10 @@
+pub mod errors;
+pub mod cache;
+pub mod cache_control;
+pub mod cache_rules;
+pub mod canonical_url;
+pub mod config;
+pub mod cached_response;
+pub mod directives;
+pub mod refresh;
+pub mod request_hash;
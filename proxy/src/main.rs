@@ -1,25 +1,26 @@
-mod errors;
-mod cache;
-mod config;
+use std::{fs::{read_to_string, File}, io::BufReader, path::PathBuf, str::FromStr, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use std::{collections::{btree_map::Entry, BTreeMap}, fs::{read_to_string, File}, io::BufReader, str::{from_utf8, FromStr}, sync::Arc};
-
-use log::info;
+use log::{error, info, warn};
 use rustls::ServerConfig;
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use actix_web::{http::StatusCode, web::{self, Data}, App, HttpResponse, HttpServer};
+use actix_web::{http::StatusCode, web::{self, Data}, App, HttpResponse, HttpServer, ResponseError};
 use anyhow::{anyhow, Context};
-use cache::{cache::{BinaryCache, Cache}, mem_cache::BinaryMemCache};
+use join_proxy::cache::{cache::{BinaryCache, Cache, PutOptions}, compressed_cache::CompressedCache, disk_cache::DiskCache, encrypted_cache::{EncryptedCache, EncryptionKeys}, mem_cache::BinaryMemCache, memcached_cache::MemcachedCache, redis_cache::RedisCache, tiered_cache::TieredCache};
 use clap::Parser;
-use errors::{InvalidHeaderNameError, InvalidHeaderValueError, MyCorruptedDBError, MyResult};
+use join_proxy::cache_control::{conditional_headers, UpstreamFreshness};
+use join_proxy::cache_rules::{cache_ttl, response_ttl, upstream_ttl};
+use join_proxy::canonical_url::canonicalize;
+use join_proxy::cached_response::{CachedResponse, FetchInfo};
+use join_proxy::directives::{CacheMode, RequestDirectives, DIRECTIVE_HEADERS};
+use join_proxy::errors::{InvalidHeaderNameError, InvalidHeaderValueError, MyResult};
+use join_proxy::refresh::{can_revalidate, locked_reads, retention, Freshness, Refreshes};
+use join_proxy::request_hash::{cache_key, canonical_json, request_hash, KeyHeaders};
 use reqwest::ClientBuilder;
 use ic_agent::Agent;
 use candid::{Decode, Encode};
-use sha2::{Digest, Sha256};
-use tokio::sync::Mutex;
 use anyhow::bail;
 
-use crate::config::Config;
+use join_proxy::config::{CacheBackend, CacheConfig, Config};
 
 #[derive(clap::Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -33,67 +34,47 @@ struct State {
     agent: Option<Agent>,
     additional_response_headers: Arc<Vec<(http_for_actix::HeaderName, http_for_actix::HeaderValue)>>,
     response_headers_to_remove: Arc<Vec<http_for_actix::HeaderName>>,
+    refreshes: Arc<Refreshes>,
 }
 
-// Two similar functions with different data types follow:
-
-fn serialize_http_request(request: &actix_web::HttpRequest, url: &str, bytes: &actix_web::web::Bytes) -> anyhow::Result<Vec<u8>> {
-    // Actix convert headers to lowercase.
-    let mut headers = BTreeMap::new();
-    for (k, v) in request.headers().into_iter() { // lexigraphical order
-        let entry = headers.entry(k.as_str());
-        let v_str = v.to_str()?;
-        match entry {
-            Entry::Vacant(vacant_entry) => {
-                vacant_entry.insert(vec![v_str]);
-            }
-            Entry::Occupied(mut occupied_entry) => {
-                occupied_entry.get_mut().push(v_str);
+/// `None` after the year 9999, that HTTP dates can't express.
+fn http_date(time: SystemTime) -> Option<http_for_actix::HeaderValue> {
+    let max = UNIX_EPOCH + Duration::from_secs(253_402_300_799);
+    (time <= max).then(|| http_for_actix::HeaderValue::from_str(&httpdate::fmt_http_date(time)).unwrap())
+}
+
+/// Tells the client how old the response is and when it expires.
+fn add_fetch_info_headers(headers: &mut actix_web::http::header::HeaderMap, fetch_info: &FetchInfo, config: &Config) {
+    if config.response_headers.show_age {
+        let age = fetch_info.age(SystemTime::now()).as_secs();
+        headers.insert(actix_web::http::header::AGE, http_for_actix::HeaderValue::from(age));
+    }
+    if config.response_headers.show_cache_times {
+        let times = [("x-joinproxy-cached-at", fetch_info.fetched_at), ("x-joinproxy-expires-at", fetch_info.expires_at())];
+        for (name, time) in times {
+            if let Some(value) = http_date(time) {
+                headers.insert(http_for_actix::HeaderName::from_static(name), value);
             }
         }
     }
-    let headers_list = headers.into_iter()
-        .map(|(k, v)| k.to_string() + "\t" + &v.join("\t"))
-        .collect::<Vec<_>>();
-    let headers_joined = headers_list.into_iter().reduce(|a, b| a + "\r" + &b);
-    let headers_joined = headers_joined.unwrap_or_else(|| "".to_string());
-    let header_part = request.method().as_str().to_owned() + "\n" + url + "\n" + &headers_joined;
-
-    Ok([header_part.as_bytes(), b"\n", bytes.to_vec().as_slice()].concat())
-}
-
-async fn serialize_http_response(response: reqwest::Response) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
-    let headers_list = response.headers().into_iter()
-        .map(|(k, v)| -> anyhow::Result<String> {
-            Ok(k.to_string() + "\t" + v.to_str()?)
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
-    let headers_joined = headers_list.into_iter().reduce(|a, b| a + "\r" + &b);
-    let headers_joined = headers_joined.unwrap_or_else(|| "".to_string());
-    let header_part = response.status().as_u16().to_string() + "\n" + &headers_joined;
-
-    let bytes = response.bytes().await?;
-    Ok(([header_part.as_bytes(), b"\n", &bytes].concat(), bytes.to_vec()))
 }
 
-fn deserialize_http_response(data: &[u8]) -> anyhow::Result<actix_web::HttpResponse<Vec<u8>>> {
-    let mut iter1 = data.splitn(3, |&c| c == b'\n');
-    let status_code_bytes = iter1.next().ok_or_else(|| MyCorruptedDBError::default())?;
-    let headers_bytes = iter1.next().ok_or_else(|| MyCorruptedDBError::default())?;
-    let body = iter1.next().ok_or_else(|| MyCorruptedDBError::default())?;
-
-    let status_code: u16 = str::parse(from_utf8(status_code_bytes)?)?;
-    let mut response = actix_web::HttpResponse::with_body(
-        StatusCode::from_u16(status_code)?, Vec::from(body));
-
-    let headers = response.headers_mut();
-    for header_str in headers_bytes.split(|&c| c == b'\r') {
-        let mut iter2 = header_str.splitn(2, |&c| c == b'\t');
-        let k = iter2.next().ok_or_else(|| MyCorruptedDBError::default())?;
-        let v = iter2.next().ok_or_else(|| MyCorruptedDBError::default())?;
-        headers.append(http_for_actix::HeaderName::from_bytes(k)?, http_for_actix::HeaderValue::from_bytes(v)?);
+fn cached_http_response(cached: CachedResponse, freshness: Freshness, config: &Config) -> MyResult<HttpResponse<web::Bytes>> {
+    let fetch_info = cached.fetch_info.clone();
+    let mut response = cached.into_http_response()?;
+    if let Some(fetch_info) = &fetch_info {
+        add_fetch_info_headers(response.headers_mut(), fetch_info, config);
+    }
+    if config.response_headers.show_hit_miss {
+        response.headers_mut().append(
+            http_for_actix::HeaderName::from_str("X-JoinProxy-Response").unwrap(),
+            http_for_actix::HeaderValue::from_str(match freshness {
+                Freshness::Stale => "Stale",
+                Freshness::StaleIfError => "StaleIfError",
+                _ => "Hit",
+            }).unwrap(),
+        );
     }
-
     Ok(response)
 }
 
@@ -104,7 +85,16 @@ fn obtain_upstream_base_url(req: &actix_web::HttpRequest) -> anyhow::Result<Stri
     Ok("https://".to_string() + host)
 }
 
-async fn prepare_request(req: &actix_web::HttpRequest, url: String, body: &web::Bytes, config: &Data<Config>, state: &Data<State>)
+/// `revalidated` is an expired response to ask upstream whether it changed.
+async fn prepare_request(
+    req: &actix_web::HttpRequest,
+    url: String,
+    body: &web::Bytes,
+    timeout: Option<Duration>,
+    revalidated: Option<&CachedResponse>,
+    config: &Data<Config>,
+    state: &Data<State>,
+)
     -> MyResult<(reqwest::Request, String)>
 {
     let uri = http::Uri::from_str(url.as_str())?;
@@ -112,6 +102,7 @@ async fn prepare_request(req: &actix_web::HttpRequest, url: String, body: &web::
     // TODO: a wrong preliminary optimization below:
     let request_headers = req.headers().into_iter()
         .map(|h| (h.0.clone(), h.1.clone()))
+        .filter(|h| !DIRECTIVE_HEADERS.contains(&h.0.as_str()))
         .filter(|h|
             !config.request_headers.remove.contains(&h.0.to_string()) ||
                 h.0 == http_for_actix::HeaderName::from_static("host"))
@@ -141,7 +132,7 @@ async fn prepare_request(req: &actix_web::HttpRequest, url: String, body: &web::
         });
     
     let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes())?;
-    let headers = http::HeaderMap::from_iter(
+    let mut headers = http::HeaderMap::from_iter(
         request_headers
             .map(|h| -> MyResult<_> {
                 Ok((
@@ -152,19 +143,60 @@ async fn prepare_request(req: &actix_web::HttpRequest, url: String, body: &web::
             .into_iter()
             .collect::<MyResult<Vec<_>>>()?
     );
-    let builder = state.client.request(method, url).headers(headers).body(Vec::from(body.as_ref()));
+    if let Some(revalidated) = revalidated {
+        headers.extend(conditional_headers(revalidated));
+    }
+    let mut builder = state.client.request(method, url).headers(headers).body(body.clone());
+    if let Some(timeout) = timeout {
+        let timeout = config.upstream_timeouts.total_timeout.map_or(timeout, |total| timeout.min(total));
+        builder = builder.timeout(timeout);
+    }
     Ok((builder.build()?, host.to_string()))
 }
 
+/// Fetches a cached response again, while the previous one is served.
+///
+/// The callback isn't asked, as the request was already authorized when it was first fetched.
+/// A failure or a response that can't be cached keeps the previous response.
+async fn refresh(
+    key: Vec<u8>, request: reqwest::Request, host: String, ttl: Duration, config: Data<Config>, cache: Data<BinaryCache>, state: Data<State>,
+) {
+    let result: MyResult<()> = async {
+        let mut cache_lock = cache.lock(&key).await?;
+        let fetched_at = SystemTime::now();
+        let fetch_start = Instant::now();
+        let reqwest_response = state.client.execute(request).await?;
+        let status = reqwest_response.status().as_u16();
+        let upstream = UpstreamFreshness::from_headers(reqwest_response.headers(), SystemTime::now());
+        let ttl = response_ttl(&config.cache, status, ttl)
+            .and_then(|ttl| upstream_ttl(&config.cache, &host, upstream, ttl));
+        let Some(ttl) = ttl else {
+            info!("Refresh: the response with status {status} is not cacheable, keeping the previous one.");
+            return Ok(());
+        };
+        let mut cached = CachedResponse::from_reqwest(reqwest_response).await?;
+        cached.fetch_info = Some(FetchInfo { fetched_at, latency: fetch_start.elapsed(), upstream_host: host.clone(), ttl, retry_after: None });
+        let options = PutOptions { ttl: Some(ttl + retention(&config.cache, &cached)), upstream_host: Some(host) };
+        cache_lock.set_with_options(Some(cached.serialize()), options).await;
+        info!("Refreshed the cached response.");
+        Ok(())
+    }.await;
+    if let Err(e) = result {
+        warn!("Cannot refresh the cached response: {e}");
+    }
+    state.refreshes.finish(&key);
+}
+
 async fn proxy(
     req: actix_web::HttpRequest,
     body: web::Bytes,
     config: Data<Config>,
-    cache: Data<Arc<tokio::sync::Mutex<Box<BinaryCache>>>>,
+    cache: Data<BinaryCache>,
     state: Data<State>, 
 )
-    -> MyResult<actix_web::HttpResponse<Vec<u8>>>
+    -> MyResult<actix_web::HttpResponse<web::Bytes>>
 {
+    let received_at = SystemTime::now();
     let path = req.uri().path_and_query().ok_or(anyhow!("can't get path and query"))?.as_str();
     info!("Joining proxy received a request to {}", path);
     // First level of defence: X-JoinProxy-Key can be stolen by an IC replica owner:
@@ -174,115 +206,392 @@ async fn proxy(
             .map(|v| v.to_str().map_err(|_| anyhow!("Cannot read header X-JoinProxy-Key")))
             .transpose()?;
         if passed_key != Some(&("Bearer ".to_string() + &our_secret)) {
-            return Ok(HttpResponse::with_body(StatusCode::NETWORK_AUTHENTICATION_REQUIRED, Vec::new()));
+            return Ok(HttpResponse::with_body(StatusCode::NETWORK_AUTHENTICATION_REQUIRED, web::Bytes::new()));
         }
     }
 
-    // TODO: Test that it works for paths like `/xx?` with question sign but without arguments.
-    // TODO: Check that https://example.com and https://example.com/ are exchangeable.
-    let serialized_request = serialize_http_request(&req, path, &body)?;
-    let actix_request_hash = Sha256::digest(serialized_request.as_slice());
+    let rule_host = req.headers().get("host")
+        .and_then(|h| http_for_actix::uri::Authority::try_from(h.as_bytes()).ok())
+        .map(|authority| authority.host().to_string())
+        .unwrap_or_default();
+    // The callback hash covers the request as sent, so that canisters can compute it.
+    let callback_hash = request_hash(req.method().as_str(), path, req.headers(), &body, |_| true)?;
+    let canonical_url = config.cache.key.canonical_url_per_host.get(&rule_host).unwrap_or(&config.cache.key.canonical_url);
+    // Used both in the cache key and upstream.
+    let canonical_path = canonicalize(path, canonical_url);
+    let key_headers = KeyHeaders::for_host(&config.cache.key, &rule_host);
+    // Only for the cache key, the body is sent upstream as is.
+    let canonical_body = config.cache.key.canonical_json_per_host.get(&rule_host).copied()
+        .unwrap_or(config.cache.key.canonical_json)
+        .then(|| canonical_json(req.headers(), &body))
+        .flatten();
+    let key_hash = if key_headers.is_all() && canonical_path == path && canonical_body.is_none() {
+        callback_hash.clone()
+    } else {
+        let key_body = canonical_body.as_deref().unwrap_or(&body);
+        // The length of the original body would tell the equivalent bodies apart.
+        let include = |name: &str| key_headers.includes(name) && !(canonical_body.is_some() && name == "content-length");
+        request_hash(req.method().as_str(), &canonical_path, req.headers(), key_body, include)?
+    };
+    let cache_key = cache_key(&key_hash);
+    // Rules match the canonical path, so that escapes can't get a request past them.
+    let rule_path = canonical_path.split_once('?').map_or(canonical_path.as_str(), |(path, _)| path);
+    let ttl = cache_ttl(&config.cache, req.method().as_str(), &rule_host, rule_path);
+    let directives = RequestDirectives::from_headers(req.headers(), config.cache.max_directive_ttl.unwrap_or(config.cache.cache_timeout))?;
+    let ttl = match directives.cache {
+        CacheMode::Bypass => None,
+        // A rule forbidding caching can't be overridden by the canister.
+        _ => ttl.map(|ttl| directives.ttl.unwrap_or(ttl)).filter(|ttl| !ttl.is_zero()),
+    };
 
-    let mut cache = (***cache).lock().await;
+    // A response being refreshed in background is served without waiting for the lock the refresh holds.
+    let refreshing = match ttl {
+        Some(_) if directives.cache != CacheMode::Refresh => state.refreshes.value(&cache_key),
+        _ => None,
+    };
+    if let Some(serialized_response) = refreshing {
+        let cached = CachedResponse::deserialize(&serialized_response)?;
+        match Freshness::of(cached.fetch_info.as_ref(), SystemTime::now(), &config.cache) {
+            Freshness::StaleIfError | Freshness::Expired => {} // Wait for the refresh.
+            freshness => {
+                info!("Cache hit, the response is being refreshed.");
+                return cached_http_response(cached, freshness, &config);
+            }
+        }
+    }
 
     // We lock during the time of downloading from upstream to prevent duplicate requests with identical data.
-    let mut cache_lock = cache.lock(&Vec::from(actix_request_hash.as_slice())).await?;
+    // Requests that must not be cached aren't locked, either.
+    let mut cache_lock = match ttl {
+        Some(_) => Some(cache.lock(&cache_key).await?),
+        None => None,
+    };
+    let cached_value = match &cache_lock {
+        Some(cache_lock) => cache_lock.inner().await,
+        None => None,
+    };
+    let cached_value = cached_value
+        .map(|serialized_response| -> MyResult<_> {
+            let cached = CachedResponse::deserialize(&serialized_response)?;
+            Ok((serialized_response, cached))
+        })
+        .transpose()?
+        // A refresh skips only responses received before the request came,
+        // so that identical requests waiting for the lock get the refreshed one.
+        .filter(|(_, cached)| directives.cache != CacheMode::Refresh ||
+            cached.fetch_info.as_ref().is_some_and(|fetch_info| fetch_info.fetched_at + fetch_info.latency >= received_at));
+    // Entries are kept in the cache after they expire, to be served while they are refreshed,
+    // when upstream fails, or to be revalidated.
+    let (cached, expired) = match cached_value {
+        Some((serialized_response, cached)) => {
+            let now = SystemTime::now();
+            match Freshness::of(cached.fetch_info.as_ref(), now, &config.cache) {
+                // Upstream failed recently, don't ask it again yet.
+                Freshness::StaleIfError if cached.fetch_info.as_ref().and_then(|info| info.retry_after).is_some_and(|retry_after| retry_after > now) =>
+                    (Some((serialized_response, cached, Freshness::StaleIfError)), None),
+                freshness @ (Freshness::StaleIfError | Freshness::Expired) => (None, Some((cached, freshness))),
+                freshness => (Some((serialized_response, cached, freshness)), None),
+            }
+        }
+        None => (None, None),
+    };
 
-    if let Some(serialized_response) = (*cache_lock).inner().await
+    if let Some((serialized_response, cached, freshness)) = cached
     {
         std::mem::drop(cache_lock);
         info!("Cache hit.");
 
-        let mut response = deserialize_http_response(serialized_response.as_slice())?;
-        if config.response_headers.show_hit_miss {
-            response.headers_mut().append(
-                http_for_actix::HeaderName::from_str("X-JoinProxy-Response").unwrap(),
-                http_for_actix::HeaderValue::from_str("Hit").unwrap(),
-            );
+        let refresh_needed = match (freshness, &config.cache.refresh_ahead, &cached.fetch_info) {
+            (Freshness::Stale, _, _) => true,
+            (Freshness::RefreshAhead, Some(refresh_ahead), Some(fetch_info)) => state.refreshes.count_ahead_hit(
+                &cache_key, fetch_info.expires_at(), refresh_ahead.min_hits, SystemTime::now()),
+            _ => false,
+        };
+        if let (true, Some(ttl)) = (refresh_needed, ttl) {
+            if state.refreshes.start(&cache_key, serialized_response) {
+                info!("Refreshing the response in background.");
+                let request = async {
+                    let base_url = obtain_upstream_base_url(&req)?;
+                    prepare_request(&req, base_url + &canonical_path, &body, directives.timeout, None, &config, &state).await
+                }.await;
+                match request {
+                    Ok((request, host)) => {
+                        actix_web::rt::spawn(refresh(
+                            cache_key, request, host, ttl, config.clone(), cache.clone(), state.clone()));
+                    }
+                    Err(e) => {
+                        warn!("Cannot refresh the cached response: {e}");
+                        state.refreshes.finish(&cache_key);
+                    }
+                }
+            }
         }
-        Ok(response)
+
+        cached_http_response(cached, freshness, &config)
     } else {
         info!("Cache miss.");
 
-        // Second level of defence: Ask back the calling canister.
-        // Do it only once per outcall (our response content isn't secure anyway).
-        if let (Some(agent), Some(callback)) = (&state.agent, &config.callback) {
-            info!("Callback...");
-            let res = agent.update(&callback.canister, &callback.func)
-                .with_arg(Encode!(&actix_request_hash.as_slice())?).call_and_wait().await;
-            match res {
-                Ok(res) => {
-                    Decode!(res.as_slice()).context("Callback decode")?; // checking for errors
-                    info!("Callback OK.");
-                }
-                Err(e) => {
-                    info!("Callback failed: {e}");
-                    Err(e)?;
-                }
+        if directives.cache == CacheMode::OnlyIfCached {
+            let mut response = HttpResponse::with_body(StatusCode::GATEWAY_TIMEOUT, web::Bytes::new());
+            if config.response_headers.show_hit_miss {
+                response.headers_mut().append(
+                    http_for_actix::HeaderName::from_str("X-JoinProxy-Response").unwrap(),
+                    http_for_actix::HeaderValue::from_str("Miss").unwrap(),
+                );
             }
+            return Ok(response);
         }
 
-        let base_url = obtain_upstream_base_url(&req)?;
-        let (reqwest, host) = prepare_request(&req, base_url + path, &body, &config, &state).await?;
-        let reqwest_response = state.client.execute(reqwest).await?;
-        info!("Upstream status: {}", reqwest_response.status());
-        let status = reqwest_response.status().as_u16();
+        // A request with its own conditions gets upstream's answer to them.
+        let conditional = [http_for_actix::header::IF_NONE_MATCH, http_for_actix::header::IF_MODIFIED_SINCE].iter()
+            .any(|name| req.headers().contains_key(name));
+        let revalidated = match &expired {
+            Some((cached, _)) if !conditional && can_revalidate(cached, SystemTime::now(), &config.cache) => Some(cached.clone()),
+            _ => None,
+        };
+        let stale = expired.and_then(|(cached, freshness)| (freshness == Freshness::StaleIfError).then_some(cached));
+
+        // Failures are shared with the requests waiting for this one for a short time,
+        // so that they don't go upstream one after another.
+        // A refresh doesn't replace a good response with a failure.
+        let failure_ttl = Some(config.cache.failure_ttl)
+            .filter(|ttl| !ttl.is_zero() && directives.cache != CacheMode::Refresh);
+
+        let result: MyResult<HttpResponse<web::Bytes>> = async {
+            // Second level of defence: Ask back the calling canister.
+            // Do it only once per outcall (our response content isn't secure anyway).
+            if let (Some(agent), Some(callback)) = (&state.agent, &config.callback) {
+                info!("Callback...");
+                let res = agent.update(&callback.canister, &callback.func)
+                    .with_arg(Encode!(&callback_hash.as_slice())?).call_and_wait().await;
+                match res {
+                    Ok(res) => {
+                        Decode!(res.as_slice()).context("Callback decode")?; // checking for errors
+                        info!("Callback OK.");
+                    }
+                    Err(e) => {
+                        info!("Callback failed: {e}");
+                        Err(e)?;
+                    }
+                }
+            }
 
-        let mut actix_response = actix_web::HttpResponse::new(
-            StatusCode::from_u16(status)?);
-        let headers = actix_response.headers_mut();
-        for (k, v) in reqwest_response.headers() {
-            headers.append(
-                http_for_actix::HeaderName::from_str(k.as_str()).map_err(|_| InvalidHeaderNameError::default())?,
-                http_for_actix::HeaderValue::from_str(v.to_str()?).map_err(|_| InvalidHeaderValueError::default())?,
-            );
-        }
+            let base_url = obtain_upstream_base_url(&req)?;
+            let (reqwest, host) = prepare_request(
+                &req, base_url + &canonical_path, &body, directives.timeout, revalidated.as_ref(), &config, &state).await?;
+            let fetched_at = SystemTime::now();
+            let fetch_start = Instant::now();
+            let reqwest_response = state.client.execute(reqwest).await?;
+            info!("Upstream status: {}", reqwest_response.status());
+            let upstream = UpstreamFreshness::from_headers(reqwest_response.headers(), SystemTime::now());
+
+            // We retrieved the response, immediately set and release the cache:
+            let not_modified = revalidated.is_some() && reqwest_response.status() == reqwest::StatusCode::NOT_MODIFIED;
+            let mut cached = match revalidated.as_ref().filter(|_| not_modified) {
+                Some(revalidated) => {
+                    info!("Not modified, the expired response is still valid.");
+                    let mut cached = revalidated.clone();
+                    cached.update_headers(reqwest_response.headers());
+                    cached
+                }
+                None => CachedResponse::from_reqwest(reqwest_response).await?,
+            };
+            let status = cached.status;
+            // The last good response is kept to be served instead.
+            let keep_stale = stale.is_some() && StatusCode::from_u16(status)?.is_server_error();
+
+            let mut actix_response = actix_web::HttpResponse::new(
+                StatusCode::from_u16(status)?);
+            let headers = actix_response.headers_mut();
+            for (k, v) in &cached.headers {
+                headers.append(
+                    http_for_actix::HeaderName::from_bytes(k).map_err(|_| InvalidHeaderNameError::default())?,
+                    http_for_actix::HeaderValue::from_bytes(v).map_err(|_| InvalidHeaderValueError::default())?,
+                );
+            }
 
-        // We retrieved the response, immediately set and release the cache:
-        let (cached, bytes) = serialize_http_response(reqwest_response).await?;
-        (*cache_lock).set(Some(cached)).await;
-        std::mem::drop(cache_lock);
+            // Errors such as 429 or 503 mustn't be served from the cache for long, if at all,
+            // but they are shared with the requests waiting for this one, unless upstream forbids storing them.
+            // Other responses that aren't cacheable, such as redirects, aren't stored at all.
+            // Only good responses are kept after they expire, to be served stale.
+            let failed = status == 429 || (500..600).contains(&status);
+            let (ttl, retention) = match ttl.map(|ttl| response_ttl(&config.cache, status, ttl)) {
+                Some(None) if failed && upstream != UpstreamFreshness::NoStore => {
+                    info!("Status {status} is not cacheable, sharing it with the waiting requests.");
+                    (failure_ttl, Duration::ZERO)
+                }
+                Some(None) => {
+                    info!("Status {status} is not cacheable.");
+                    (None, Duration::ZERO)
+                }
+                // The upstream forbidding to store the response is followed even for the waiting requests.
+                ttl => (ttl.flatten().and_then(|ttl| upstream_ttl(&config.cache, &host, upstream, ttl)), retention(&config.cache, &cached)),
+            };
+            let fetch_info = FetchInfo {
+                fetched_at,
+                latency: fetch_start.elapsed(),
+                upstream_host: host.clone(),
+                ttl: ttl.unwrap_or_default(),
+                retry_after: None,
+            };
+            info!("Upstream latency: {:?}", fetch_info.latency);
+            cached.fetch_info = Some(fetch_info);
+            if let (Some(cache_lock), Some(_), false) = (&mut cache_lock, ttl, keep_stale) {
+                add_fetch_info_headers(headers, cached.fetch_info.as_ref().unwrap(), &config);
+                let options = PutOptions { ttl: ttl.map(|ttl| ttl + retention), upstream_host: Some(host.clone()) };
+                cache_lock.set_with_options(Some(cached.serialize()), options).await;
+            }
+            let bytes = cached.body;
+            if !keep_stale {
+                cache_lock = None; // Let the waiting requests proceed.
+            }
 
-        if config.response_headers.show_hit_miss {
-            headers.append(
-                http_for_actix::HeaderName::from_str("X-JoinProxy-Response").unwrap(),
-                http_for_actix::HeaderValue::from_str("Miss").unwrap(),
-            );
-        }
-        if config.response_headers.add_forwarded_from_header {
-            if let Some(addr) = req.head().peer_addr {
+            if config.response_headers.show_hit_miss {
                 headers.append(
-                    http_for_actix::HeaderName::from_str("X-Forwarded-For").unwrap(),
-                    http_for_actix::HeaderValue::from_str(&addr.ip().to_string()).unwrap(),
+                    http_for_actix::HeaderName::from_str("X-JoinProxy-Response").unwrap(),
+                    http_for_actix::HeaderValue::from_str(if not_modified { "Revalidated" } else { "Miss" }).unwrap(),
                 );
             }
-        }
-        for k in state.response_headers_to_remove.iter() {
-            headers.remove(k);
-        }
-        if let Some(remove) = config.response_headers.remove_per_host.get(&host) {
-            for k in remove.into_iter() {
+            if config.response_headers.add_forwarded_from_header {
+                if let Some(addr) = req.head().peer_addr {
+                    headers.append(
+                        http_for_actix::HeaderName::from_str("X-Forwarded-For").unwrap(),
+                        http_for_actix::HeaderValue::from_str(&addr.ip().to_string()).unwrap(),
+                    );
+                }
+            }
+            for k in state.response_headers_to_remove.iter() {
                 headers.remove(k);
             }
-        }
-        for (k, v) in config.response_headers.add.iter() {
-            headers.append(
-                http_for_actix::HeaderName::from_str(k).map_err(|_| InvalidHeaderNameError::default())?,
-                http_for_actix::HeaderValue::from_str(&v).map_err(|_| InvalidHeaderValueError::default())?
-            );
-        }
-        if let Some(add) = config.response_headers.add_per_host.get(&host) {
-            for (k, v) in add.into_iter() {
+            if let Some(remove) = config.response_headers.remove_per_host.get(&host) {
+                for k in remove.into_iter() {
+                    headers.remove(k);
+                }
+            }
+            for (k, v) in config.response_headers.add.iter() {
                 headers.append(
                     http_for_actix::HeaderName::from_str(k).map_err(|_| InvalidHeaderNameError::default())?,
                     http_for_actix::HeaderValue::from_str(&v).map_err(|_| InvalidHeaderValueError::default())?
                 );
             }
+            if let Some(add) = config.response_headers.add_per_host.get(&host) {
+                for (k, v) in add.into_iter() {
+                    headers.append(
+                        http_for_actix::HeaderName::from_str(k).map_err(|_| InvalidHeaderNameError::default())?,
+                        http_for_actix::HeaderValue::from_str(&v).map_err(|_| InvalidHeaderValueError::default())?
+                    );
+                }
+            }
+
+            Ok(actix_response.set_body(bytes))
+        }.await;
+
+        let upstream_failed = match &result {
+            Ok(response) => response.status().is_server_error(),
+            Err(_) => true,
+        };
+        if let (true, Some(mut stale)) = (upstream_failed, stale) {
+            info!("Upstream failed, serving the expired response.");
+            // The waiting requests get the expired response, too, instead of going upstream one after another.
+            if let (Some(cache_lock), Some(failure_ttl), Some(mut fetch_info)) = (&mut cache_lock, failure_ttl, stale.fetch_info.take()) {
+                let now = SystemTime::now();
+                let kept_until = fetch_info.expires_at() + retention(&config.cache, &stale);
+                fetch_info.retry_after = Some(now + failure_ttl);
+                let upstream_host = Some(fetch_info.upstream_host.clone());
+                stale.fetch_info = Some(fetch_info);
+                if let Ok(ttl) = kept_until.duration_since(now) {
+                    cache_lock.set_with_options(Some(stale.serialize()), PutOptions { ttl: Some(ttl), upstream_host }).await;
+                }
+            }
+            return cached_http_response(stale, Freshness::StaleIfError, &config);
         }
+        if let (Err(e), Some(cache_lock), Some(failure_ttl)) = (&result, &mut cache_lock, failure_ttl) {
+            info!("Caching the failure for {failure_ttl:?}.");
+            let mut failure = CachedResponse::failure(e.status_code().as_u16(), e.to_string());
+            // Expires after `failure_ttl` even in a cache that keeps it longer, such as L1 of the tiered cache.
+            failure.fetch_info = Some(FetchInfo {
+                fetched_at: SystemTime::now(),
+                latency: Duration::ZERO,
+                upstream_host: rule_host.clone(),
+                ttl: failure_ttl,
+                retry_after: None,
+            });
+            let options = PutOptions { ttl: Some(failure_ttl), upstream_host: Some(rule_host) };
+            cache_lock.set_with_options(Some(failure.serialize()), options).await;
+        }
+        result
+    }
+}
+
+async fn create_mem_cache(config: &CacheConfig, cache_timeout: Duration) -> anyhow::Result<Arc<BinaryCache>> {
+    let cache = if let Some(snapshot_file) = &config.snapshot_file {
+        BinaryMemCache::with_snapshot(cache_timeout, PathBuf::from(snapshot_file))?
+    } else {
+        BinaryMemCache::new(cache_timeout)
+    };
+    let cache = cache.with_limits(config.max_entries, config.max_bytes, config.eviction_policy);
+    cache.trim().await;
+    let cache = Arc::new(cache);
+    cache.spawn_reaper(config.reaper_interval);
+    Ok(cache)
+}
+
+async fn create_single_cache(backend: CacheBackend, config: &CacheConfig) -> anyhow::Result<Arc<BinaryCache>> {
+    Ok(match backend {
+        CacheBackend::Memory => create_mem_cache(config, config.cache_timeout).await?,
+        CacheBackend::Redis => {
+            let redis_config = config.redis.as_ref()
+                .ok_or_else(|| anyhow!("Missing [cache.redis] section for Redis backend"))?;
+            let cache = RedisCache::new(redis_config, config.cache_timeout).await?;
+            Arc::new(if locked_reads(config) { cache.with_locked_reads() } else { cache })
+        }
+        CacheBackend::Memcached => {
+            let memcached_config = config.memcached.as_ref()
+                .ok_or_else(|| anyhow!("Missing [cache.memcached] section for memcached backend"))?;
+            let cache = MemcachedCache::new(memcached_config, config.cache_timeout);
+            Arc::new(if locked_reads(config) { cache.with_locked_reads() } else { cache })
+        }
+        CacheBackend::Disk => {
+            let disk_config = config.disk.as_ref()
+                .ok_or_else(|| anyhow!("Missing [cache.disk] section for disk backend"))?;
+            let cache = Arc::new(DiskCache::new(disk_config, config.cache_timeout)?);
+            cache.spawn_reaper(config.reaper_interval);
+            cache
+        }
+        CacheBackend::Tiered => bail!("Tiered cache cannot be a tier"),
+    })
+}
 
-        // let response_body: Vec<u8> = Vec::from(bytes);
-        Ok(actix_response.set_body(bytes))
+async fn create_storage(config: &CacheConfig) -> anyhow::Result<Arc<BinaryCache>> {
+    if config.snapshot_file.is_some() && !matches!(config.backend, CacheBackend::Memory | CacheBackend::Tiered) {
+        bail!("`snapshot_file` is supported only by the memory and tiered backends");
     }
+    if config.backend == CacheBackend::Tiered {
+        let tiered_config = config.tiered.as_ref()
+            .ok_or_else(|| anyhow!("Missing [cache.tiered] section for tiered backend"))?;
+        let l1 = create_mem_cache(config, tiered_config.l1_cache_timeout.unwrap_or(config.cache_timeout)).await?;
+        let l2 = create_single_cache(tiered_config.l2, config).await?;
+        let tiered = TieredCache::new(l1, l2);
+        let tiered = if locked_reads(config) { tiered.with_locked_reads() } else { tiered };
+        Ok(Arc::new(match tiered_config.l1_cache_timeout {
+            Some(l1_cache_timeout) => tiered.with_l1_max_ttl(l1_cache_timeout),
+            None => tiered,
+        }))
+    } else {
+        create_single_cache(config.backend, config).await
+    }
+}
+
+async fn create_cache(config: &CacheConfig) -> anyhow::Result<Arc<BinaryCache>> {
+    let storage = create_storage(config).await?;
+    let keys = config.encryption.as_ref().map(EncryptionKeys::load).transpose()?;
+    // Compress before encrypting, as encrypted data doesn't compress.
+    let encrypted = Arc::new(EncryptedCache::new(storage, keys));
+    let compressed = Arc::new(CompressedCache::new(encrypted, config.compression.clone()));
+    if let Some(compression) = &config.compression {
+        compressed.spawn_stats_logger(compression.stats_interval);
+    }
+    Ok(compressed)
 }
 
 #[actix_web::main]
@@ -303,8 +612,22 @@ async fn main() -> anyhow::Result<()> {
 
     let server_url = config.serve.host.clone() + ":" + config.serve.port.to_string().as_str();
 
-    let cache =
-        Arc::new(Mutex::new(Box::<BinaryCache>::from(Box::new(BinaryMemCache::new(config.cache.cache_timeout)))));
+    let cache = create_cache(&config.cache).await?;
+    if config.cache.snapshot_file.is_some() {
+        let cache = cache.clone();
+        let snapshot_interval = config.cache.snapshot_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(snapshot_interval);
+            interval.tick().await; // The first tick completes immediately.
+            loop {
+                interval.tick().await;
+                if let Err(e) = cache.flush().await {
+                    error!("Cannot save cache: {e}");
+                }
+            }
+        });
+    }
+    let cache_to_flush = cache.clone();
 
     let additional_response_headers = &config.request_headers.add;
     let additional_response_headers = additional_response_headers.into_iter().map(
@@ -326,6 +649,8 @@ async fn main() -> anyhow::Result<()> {
     let response_headers_to_remove = response_headers_to_remove.collect::<MyResult<Vec<_>>>()?;
     let response_headers_to_remove = Arc::new(response_headers_to_remove);
 
+    let refreshes = Arc::new(Refreshes::default());
+
     let agent = {
         if let Some(callback) = &config.callback {
             let mut builder = Agent::builder();
@@ -360,12 +685,13 @@ async fn main() -> anyhow::Result<()> {
             additional_response_headers: additional_response_headers.clone(),
             response_headers_to_remove: response_headers_to_remove.clone(),
             agent: agent.clone(),
+            refreshes: refreshes.clone(),
         };
         App::new().service(
             web::scope("")
             .app_data(Data::new(config.clone()))
             .app_data(Data::new(state))
-            .app_data(Data::new(cache.clone()))
+            .app_data(Data::from(cache.clone()))
                 .route("/{_:.*}", web::route().to(proxy))
         )
     });
@@ -390,5 +716,9 @@ async fn main() -> anyhow::Result<()> {
         server.bind(server_url)
     }?
         .run()
-        .await.map_err(|e| e.into())
+        .await?;
+
+    // The server stops gracefully on SIGINT/SIGTERM, save the cache before exiting.
+    cache_to_flush.flush().await?;
+    Ok(())
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+
+use crate::{cached_response::{CachedResponse, FetchInfo}, config::CacheConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// Fresh, but should be refreshed in background if used frequently.
+    RefreshAhead,
+    /// Expired, but may be served while it is refreshed in background.
+    Stale,
+    /// Expired, but may be served if upstream fails.
+    StaleIfError,
+    /// Must not be served.
+    Expired,
+}
+
+impl Freshness {
+    pub fn of(fetch_info: Option<&FetchInfo>, now: SystemTime, config: &CacheConfig) -> Self {
+        // Entries of older versions expire by the cache backend only.
+        let Some(fetch_info) = fetch_info else {
+            return Freshness::Fresh;
+        };
+        let age = fetch_info.age(now);
+        if age >= fetch_info.ttl {
+            let expired_for = age - fetch_info.ttl;
+            return if expired_for < config.stale_while_revalidate.unwrap_or_default() {
+                Freshness::Stale
+            } else if expired_for < config.stale_if_error.unwrap_or_default() {
+                Freshness::StaleIfError
+            } else {
+                Freshness::Expired
+            };
+        }
+        match &config.refresh_ahead {
+            Some(refresh_ahead) if age.as_secs_f64() >= fetch_info.ttl.as_secs_f64() * refresh_ahead.threshold =>
+                Freshness::RefreshAhead,
+            _ => Freshness::Fresh,
+        }
+    }
+}
+
+/// How long the cache backend keeps an entry after it expires, to serve it stale or revalidate it.
+pub fn retention(config: &CacheConfig, cached: &CachedResponse) -> Duration {
+    let retention = config.stale_while_revalidate.max(config.stale_if_error);
+    let retention = if cached.has_validators() { retention.max(config.revalidation_period) } else { retention };
+    retention.unwrap_or_default()
+}
+
+/// Whether responses stay in the cache backend while they may need fetching again,
+/// so that reading them must take the lock, too.
+pub fn locked_reads(config: &CacheConfig) -> bool {
+    config.stale_while_revalidate.is_some() || config.stale_if_error.is_some() ||
+        config.revalidation_period.is_some() || config.refresh_ahead.is_some()
+}
+
+/// Whether upstream can be asked if an expired response changed, instead of downloading it again.
+pub fn can_revalidate(cached: &CachedResponse, now: SystemTime, config: &CacheConfig) -> bool {
+    match (&cached.fetch_info, config.revalidation_period) {
+        (Some(fetch_info), Some(period)) => cached.has_validators() && fetch_info.age(now) < fetch_info.ttl + period,
+        _ => false,
+    }
+}
+
+/// Background refreshes in progress in this proxy instance.
+///
+/// A refresh holds the per-key lock of the cache while it fetches from upstream,
+/// so the previous value is kept here to serve it meanwhile.
+#[derive(Default)]
+pub struct Refreshes {
+    in_progress: std::sync::Mutex<HashMap<Vec<u8>, Bytes>>,
+    /// Hits of entries past the refresh-ahead threshold, and when the entries expire.
+    ahead_hits: std::sync::Mutex<HashMap<Vec<u8>, (u32, SystemTime)>>,
+}
+
+impl Refreshes {
+    /// The value being refreshed.
+    pub fn value(&self, key: &[u8]) -> Option<Bytes> {
+        self.in_progress.lock().unwrap().get(key).cloned()
+    }
+
+    /// Returns `false` if the key is already being refreshed.
+    pub fn start(&self, key: &[u8], value: Bytes) -> bool {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        if in_progress.contains_key(key) {
+            return false;
+        }
+        in_progress.insert(key.to_vec(), value);
+        self.ahead_hits.lock().unwrap().remove(key);
+        true
+    }
+
+    pub fn finish(&self, key: &[u8]) {
+        self.in_progress.lock().unwrap().remove(key);
+    }
+
+    /// Counts a hit past the refresh-ahead threshold. Returns whether the entry is used often enough to refresh it.
+    pub fn count_ahead_hit(&self, key: &[u8], expires_at: SystemTime, min_hits: u32, now: SystemTime) -> bool {
+        let mut ahead_hits = self.ahead_hits.lock().unwrap();
+        if !ahead_hits.contains_key(key) {
+            // Forget entries that expired without being used enough.
+            ahead_hits.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        let (hits, _) = ahead_hits.entry(key.to_vec()).or_insert((0, expires_at));
+        *hits += 1;
+        *hits >= min_hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use bytes::Bytes;
+
+    use crate::cached_response::{CachedResponse, FetchInfo};
+    use crate::config::CacheConfig;
+
+    use super::{can_revalidate, retention, Freshness, Refreshes};
+
+    fn response(headers: &[(&str, &str)]) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: headers.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect(),
+            body: Bytes::from_static(b"body"),
+            fetch_info: None,
+        }
+    }
+
+    #[test]
+    fn test_freshness() {
+        let config: CacheConfig = toml::from_str(r#"
+            cache_timeout = "100s"
+            stale_while_revalidate = "50s"
+            stale_if_error = "1h"
+            refresh_ahead = { threshold = 0.8 }
+        "#).unwrap();
+        let fetched_at = SystemTime::now();
+        let fetch_info = FetchInfo {
+            fetched_at,
+            latency: Duration::ZERO,
+            upstream_host: "example.com".to_string(),
+            ttl: Duration::from_secs(100),
+            retry_after: None,
+        };
+        let cases = [
+            (0, Freshness::Fresh),
+            (79, Freshness::Fresh),
+            (80, Freshness::RefreshAhead),
+            (99, Freshness::RefreshAhead),
+            (100, Freshness::Stale),
+            (149, Freshness::Stale),
+            (150, Freshness::StaleIfError),
+            (3699, Freshness::StaleIfError),
+            (3700, Freshness::Expired),
+        ];
+        for (age, expected) in cases {
+            let now = fetched_at + Duration::from_secs(age);
+            assert_eq!(Freshness::of(Some(&fetch_info), now, &config), expected, "{age}");
+        }
+        assert_eq!(Freshness::of(None, fetched_at, &config), Freshness::Fresh);
+    }
+
+    #[test]
+    fn test_retention() {
+        let config = |toml: &str| -> CacheConfig { toml::from_str(&format!("cache_timeout = \"1s\"\n{toml}")).unwrap() };
+        let plain = response(&[]);
+        let validated = response(&[("etag", "\"1\"")]);
+        assert_eq!(retention(&config(""), &validated), Duration::ZERO);
+        assert_eq!(retention(&config(r#"stale_while_revalidate = "1m""#), &plain), Duration::from_secs(60));
+        let config = config("stale_while_revalidate = \"1m\"\nstale_if_error = \"1h\"\nrevalidation_period = \"1d\"");
+        assert_eq!(retention(&config, &plain), Duration::from_secs(3600));
+        assert_eq!(retention(&config, &validated), Duration::from_secs(24 * 3600));
+    }
+
+    #[test]
+    fn test_can_revalidate() {
+        let config: CacheConfig = toml::from_str("cache_timeout = \"1s\"\nrevalidation_period = \"1m\"").unwrap();
+        let fetched_at = SystemTime::now();
+        let mut validated = response(&[("last-modified", "Tue, 14 Nov 2023 22:13:20 GMT")]);
+        assert!(!can_revalidate(&validated, fetched_at, &config));
+        validated.fetch_info = Some(FetchInfo {
+            fetched_at,
+            latency: Duration::ZERO,
+            upstream_host: "example.com".to_string(),
+            ttl: Duration::from_secs(1),
+            retry_after: None,
+        });
+        assert!(can_revalidate(&validated, fetched_at + Duration::from_secs(60), &config));
+        assert!(!can_revalidate(&validated, fetched_at + Duration::from_secs(61), &config));
+        let plain = CachedResponse { headers: vec![], ..validated };
+        assert!(!can_revalidate(&plain, fetched_at, &config));
+    }
+
+    #[test]
+    fn test_one_refresh_at_a_time() {
+        let refreshes = Refreshes::default();
+        assert!(refreshes.start(b"key", Bytes::from_static(b"old")));
+        assert!(!refreshes.start(b"key", Bytes::from_static(b"old")));
+        assert_eq!(refreshes.value(b"key"), Some(Bytes::from_static(b"old")));
+        refreshes.finish(b"key");
+        assert_eq!(refreshes.value(b"key"), None);
+        assert!(refreshes.start(b"key", Bytes::from_static(b"new")));
+    }
+
+    #[test]
+    fn test_ahead_hits() {
+        let refreshes = Refreshes::default();
+        let now = SystemTime::now();
+        let expires_at = now + Duration::from_secs(10);
+        assert!(!refreshes.count_ahead_hit(b"key", expires_at, 3, now));
+        assert!(!refreshes.count_ahead_hit(b"key", expires_at, 3, now));
+        assert!(refreshes.count_ahead_hit(b"key", expires_at, 3, now));
+
+        // The count starts anew after a refresh.
+        assert!(refreshes.start(b"key", Bytes::new()));
+        refreshes.finish(b"key");
+        assert!(!refreshes.count_ahead_hit(b"key", expires_at, 2, now));
+
+        // Expired entries are forgotten.
+        let later = expires_at + Duration::from_secs(1);
+        assert!(!refreshes.count_ahead_hit(b"other", later + Duration::from_secs(10), 2, later));
+        assert!(!refreshes.ahead_hits.lock().unwrap().contains_key(b"key".as_slice()));
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::header::{HeaderMap, CONTENT_TYPE};
+use sha2::{Digest, Sha256};
+
+use crate::config::CacheKeyConfig;
+use crate::directives::DIRECTIVE_HEADERS;
+
+/// Version of the scheme of `request_hash`, that canisters implement to answer the callback.
+///
+/// Version 1 is SHA-256 of `method \n url \n headers \n body`, where `url` is the path with the query,
+/// and `headers` are `name \t value1 \t value2 ...` of every header, joined with `\r`,
+/// with lowercase names in lexicographical order and values in the order they were sent.
+pub const REQUEST_HASH_VERSION: u32 = 1;
+
+/// Hashes the request with the headers for which `include` is true, see `REQUEST_HASH_VERSION`.
+/// It doesn't copy the body into a serialized buffer.
+pub fn request_hash(
+    method: &str, url: &str, headers: &HeaderMap, body: &[u8], include: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<u8>> {
+    // Actix convert headers to lowercase.
+    let mut header_values = BTreeMap::<&str, Vec<&str>>::new(); // lexigraphical order
+    for (k, v) in headers.iter().filter(|(k, _)| include(k.as_str())) {
+        header_values.entry(k.as_str()).or_default().push(v.to_str()?);
+    }
+    let headers_joined = header_values.into_iter()
+        .map(|(k, v)| k.to_string() + "\t" + &v.join("\t"))
+        .collect::<Vec<_>>()
+        .join("\r");
+    let header_part = method.to_owned() + "\n" + url + "\n" + &headers_joined;
+
+    let mut hasher = Sha256::new();
+    hasher.update(header_part.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    Ok(hasher.finalize().to_vec())
+}
+
+/// The cache key of a request with the hash `hash`. It starts with `REQUEST_HASH_VERSION`,
+/// so that entries hashed by another version of the scheme are never read.
+pub fn cache_key(hash: &[u8]) -> Vec<u8> {
+    [REQUEST_HASH_VERSION.to_be_bytes().as_slice(), hash].concat()
+}
+
+/// The canonical form (RFC 8785, JSON Canonicalization Scheme) of a body with a JSON content type,
+/// `None` if it is not JSON.
+pub fn canonical_json(headers: &HeaderMap, body: &[u8]) -> Option<Vec<u8>> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if media_type != "application/json" && !media_type.ends_with("+json") {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let mut canonical = String::with_capacity(body.len());
+    write_canonical_json(&value, &mut canonical);
+    Some(canonical.into_bytes())
+}
+
+fn write_canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(object) => {
+            // Keys are sorted by their UTF-16 code units, as in JavaScript.
+            let mut entries = object.iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::from(key.as_str()).to_string());
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(array) => {
+            out.push('[');
+            for (i, value) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(value, out);
+            }
+            out.push(']');
+        }
+        // All numbers are IEEE 754 doubles, formatted as JavaScript does.
+        serde_json::Value::Number(number) => {
+            out.push_str(ryu_js::Buffer::new().format_finite(number.as_f64().unwrap_or_default()));
+        }
+        // Strings are escaped as JavaScript does, as are `null`, `true` and `false`.
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+/// Which request headers are a part of the cache key for `host`.
+pub struct KeyHeaders<'a> {
+    include: Option<&'a [String]>,
+    exclude: Vec<&'a str>,
+}
+
+impl<'a> KeyHeaders<'a> {
+    /// The per host whitelist replaces the global one, the blacklists add up.
+    pub fn for_host(config: &'a CacheKeyConfig, host: &str) -> Self {
+        let include = config.include_headers_per_host.get(host).or(config.include_headers.as_ref())
+            .map(|headers| headers.as_slice());
+        let exclude = config.exclude_headers.iter()
+            .chain(config.exclude_headers_per_host.get(host).into_iter().flatten())
+            .map(|header| header.as_str())
+            .collect();
+        Self { include, exclude }
+    }
+
+    /// Whether all headers are included, so that the cache key is the same as the callback hash.
+    pub fn is_all(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    /// `Host` and the directive headers are always included: the upstream and the caching mode are a part of the key.
+    pub fn includes(&self, name: &str) -> bool {
+        if name.eq_ignore_ascii_case("host") || DIRECTIVE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            return true;
+        }
+        self.include.is_none_or(|include| include.iter().any(|h| h.eq_ignore_ascii_case(name))) &&
+            !self.exclude.iter().any(|h| h.eq_ignore_ascii_case(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use sha2::{Digest, Sha256};
+
+    use crate::config::CacheKeyConfig;
+
+    use super::{cache_key, canonical_json, request_hash, KeyHeaders, REQUEST_HASH_VERSION};
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (k, v) in headers {
+            map.append(HeaderName::from_static(k), HeaderValue::from_static(v));
+        }
+        map
+    }
+
+    #[test]
+    fn test_version_1() {
+        let headers = headers(&[("x-b", "2"), ("x-a", "1"), ("x-b", "3")]);
+        let expected = Sha256::digest(b"POST\n/v1/embeddings?x=1\nx-a\t1\rx-b\t2\t3\n{\"input\":\"hi\"}").to_vec();
+        let hash = request_hash("POST", "/v1/embeddings?x=1", &headers, b"{\"input\":\"hi\"}", |_| true).unwrap();
+        assert_eq!(hash, expected);
+        let expected = Sha256::digest(b"GET\n/\n\n").to_vec();
+        assert_eq!(request_hash("GET", "/", &HeaderMap::new(), b"", |_| true).unwrap(), expected);
+        assert_eq!(cache_key(&expected), [[0, 0, 0, REQUEST_HASH_VERSION as u8].as_slice(), &expected].concat());
+    }
+
+    #[test]
+    fn test_key_headers() {
+        let config: CacheKeyConfig = toml::from_str(r#"
+            exclude_headers = ["traceparent", "X-JoinProxy-Key"]
+            include_headers_per_host = { "api.openai.com" = ["authorization", "content-type", "traceparent"] }
+            exclude_headers_per_host = { "api.pinecone.io" = ["user-agent"] }
+        "#).unwrap();
+        let cases: &[(&str, &str, bool)] = &[
+            ("example.com", "user-agent", true),
+            ("example.com", "traceparent", false),
+            ("example.com", "x-joinproxy-key", false),
+            ("api.openai.com", "authorization", true),
+            ("api.openai.com", "user-agent", false),
+            ("api.openai.com", "traceparent", false),
+            ("api.pinecone.io", "user-agent", false),
+            ("api.pinecone.io", "api-key", true),
+            ("api.openai.com", "host", true),
+            ("api.openai.com", "x-joinproxy-cache-ttl", true),
+        ];
+        for (host, header, expected) in cases {
+            assert_eq!(KeyHeaders::for_host(&config, host).includes(header), *expected, "{host} {header}");
+        }
+        assert!(!KeyHeaders::for_host(&config, "example.com").is_all());
+        assert!(KeyHeaders::for_host(&CacheKeyConfig::default(), "example.com").is_all());
+    }
+
+    #[test]
+    fn test_excluded_headers_share_the_key() {
+        let config: CacheKeyConfig = toml::from_str(r#"exclude_headers = ["traceparent"]"#).unwrap();
+        let key_headers = KeyHeaders::for_host(&config, "example.com");
+        let key = |headers: &HeaderMap| request_hash("GET", "/", headers, b"", |name| key_headers.includes(name)).unwrap();
+        let first = headers(&[("accept", "text/plain"), ("traceparent", "00-1")]);
+        let second = headers(&[("accept", "text/plain"), ("traceparent", "00-2")]);
+        let other = headers(&[("accept", "application/json"), ("traceparent", "00-1")]);
+        assert_eq!(key(&first), key(&second));
+        assert_ne!(key(&first), key(&other));
+    }
+
+    #[test]
+    fn test_whitelist_keeps_hosts_apart() {
+        let config: CacheKeyConfig = toml::from_str(r#"include_headers = ["authorization"]"#).unwrap();
+        let key_headers = KeyHeaders::for_host(&config, "example.com");
+        let key = |headers: &HeaderMap| request_hash("GET", "/", headers, b"", |name| key_headers.includes(name)).unwrap();
+        let first = headers(&[("host", "a.example.com"), ("authorization", "Bearer 1"), ("accept", "text/plain")]);
+        let second = headers(&[("host", "b.example.com"), ("authorization", "Bearer 1"), ("accept", "text/plain")]);
+        let refresh = headers(&[("host", "a.example.com"), ("authorization", "Bearer 1"), ("x-joinproxy-cache", "refresh")]);
+        let other_accept = headers(&[("host", "a.example.com"), ("authorization", "Bearer 1"), ("accept", "text/html")]);
+        assert_ne!(key(&first), key(&second));
+        assert_ne!(key(&first), key(&refresh));
+        assert_eq!(key(&first), key(&other_accept));
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        let json = headers(&[("content-type", "application/json; charset=utf-8")]);
+        let cases: &[(&str, Option<&str>)] = &[
+            (r#"{"b": 1, "a": [true, null, "x"]}"#, Some(r#"{"a":[true,null,"x"],"b":1}"#)),
+            (r#"{ "model" : "gpt-4o",
+                 "messages": [{"role": "user", "content": "hi"}] }"#,
+                Some(r#"{"messages":[{"content":"hi","role":"user"}],"model":"gpt-4o"}"#)),
+            // Examples from RFC 8785.
+            (r#"{"numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001]}"#,
+                Some(r#"{"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27]}"#)),
+            (r#"{"€": "Euro Sign", "\r": "Carriage Return", "\ufb33": "Dalet", "1": "One", "😀": "Emoji", "\u0080": "Control", "ö": "O"}"#,
+                Some("{\"\\r\":\"Carriage Return\",\"1\":\"One\",\"\u{80}\":\"Control\",\"ö\":\"O\",\"€\":\"Euro Sign\",\"😀\":\"Emoji\",\"\u{fb33}\":\"Dalet\"}")),
+            (r#"{"literals": [null, true, false], "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/"}"#,
+                Some(r#"{"literals":[null,true,false],"string":"€$\u000f\nA'B\"\\\\\"/"}"#)),
+            ("[-0, 1.0, 100, 1e21, 9007199254740993]", Some("[0,1,100,1e+21,9007199254740992]")),
+            (r#"{"a": 1"#, None),
+            ("", None),
+        ];
+        for (body, expected) in cases {
+            let canonical = canonical_json(&json, body.as_bytes()).map(|c| String::from_utf8(c).unwrap());
+            assert_eq!(canonical.as_deref(), *expected, "{body}");
+        }
+
+        let body = br#"{"b": 1, "a": 2}"#;
+        assert!(canonical_json(&headers(&[("content-type", "Application/Problem+JSON")]), body).is_some());
+        assert!(canonical_json(&headers(&[("content-type", "text/plain")]), body).is_none());
+        assert!(canonical_json(&HeaderMap::new(), body).is_none());
+    }
+}
@@ -246,4 +246,174 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Concurrent identical requests that fail upstream must reach it only once.
+    #[tokio::test]
+    async fn test_failure_is_shared() -> Result<(), Box<dyn std::error::Error>> {
+        let mytest = MyTest::new().await?;
+
+        // Call the proxy directly, without IC and the callback.
+        let toml_path = mytest.test.dir.path().join("config.toml");
+        let mut doc = read_to_string(&toml_path)?.parse::<DocumentMut>().context("Invalid TOML")?;
+        doc.remove("callback");
+        doc["cache"]["failure_ttl"] = value("10s");
+        write(&toml_path, doc.to_string()).context("Writing modified config.")?;
+        let _proxy = TemporaryChild::spawn(&mut Command::new(
+            mytest.test.workspace_dir.join("target").join("debug").join("join-proxy")
+        ).current_dir(mytest.test.dir.path()), Capture { stdout: None, stderr: None }).context("Running Joining Proxy")?;
+        sleep(Duration::from_millis(1000)).await; // Wait till the proxy starts.
+
+        // HTTP/1.1, as the proxy takes the upstream from the `Host` header.
+        let client = reqwest::Client::builder().http1_only().build()?;
+        let request = || client.get("https://local.vporton.name:8443/slow?delay_ms=3000")
+            .header("host", "local.vporton.name:8081")
+            .header("x-joinproxy-timeout", "1s")
+            .send();
+        let responses = join!(request(), request(), request(), request(), request());
+        let responses = [responses.0?, responses.1?, responses.2?, responses.3?, responses.4?];
+        let (mut hit_count, mut miss_count) = (0, 0);
+        for response in &responses {
+            assert_eq!(response.status(), reqwest::StatusCode::GATEWAY_TIMEOUT); // the upstream request timed out
+            match response.headers().get("x-joinproxy-response").map(|v| v.as_bytes()) {
+                Some(b"Hit") => hit_count += 1,
+                None => miss_count += 1, // the failed request itself
+                Some(v) => panic!("unexpected X-JoinProxy-Response: {v:?}"),
+            }
+        }
+        assert_eq!((miss_count, hit_count), (1, 4));
+
+        let hits = client.get("https://local.vporton.name:8081/hits").send().await?.text().await?;
+        assert_eq!(hits, "1");
+
+        drop(mytest);
+
+        Ok(())
+    }
+
+    /// Concurrent identical requests with `X-JoinProxy-Cache: refresh` go upstream only once.
+    #[tokio::test]
+    async fn test_refresh_is_shared() -> Result<(), Box<dyn std::error::Error>> {
+        let mytest = MyTest::new().await?;
+
+        // Call the proxy directly, without IC and the callback.
+        let toml_path = mytest.test.dir.path().join("config.toml");
+        let mut doc = read_to_string(&toml_path)?.parse::<DocumentMut>().context("Invalid TOML")?;
+        doc.remove("callback");
+        write(&toml_path, doc.to_string()).context("Writing modified config.")?;
+        let _proxy = TemporaryChild::spawn(&mut Command::new(
+            mytest.test.workspace_dir.join("target").join("debug").join("join-proxy")
+        ).current_dir(mytest.test.dir.path()), Capture { stdout: None, stderr: None }).context("Running Joining Proxy")?;
+        sleep(Duration::from_millis(1000)).await; // Wait till the proxy starts.
+
+        // HTTP/1.1, as the proxy takes the upstream from the `Host` header.
+        let client = reqwest::Client::builder().http1_only().build()?;
+        let request = |mode: &'static str| client.get("https://local.vporton.name:8443/slow?delay_ms=500")
+            .header("host", "local.vporton.name:8081")
+            .header("x-joinproxy-cache", mode)
+            .send();
+        let response = request("refresh").await?;
+        assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "Miss");
+
+        let responses = join!(request("refresh"), request("refresh"), request("refresh"));
+        let responses = [responses.0?, responses.1?, responses.2?];
+        let (mut hit_count, mut miss_count) = (0, 0);
+        for response in &responses {
+            match response.headers().get("x-joinproxy-response").map(|v| v.as_bytes()) {
+                Some(b"Hit") => hit_count += 1,
+                Some(b"Miss") => miss_count += 1,
+                v => panic!("unexpected X-JoinProxy-Response: {v:?}"),
+            }
+        }
+        assert_eq!((miss_count, hit_count), (1, 2));
+
+        let hits = client.get("https://local.vporton.name:8081/hits").send().await?.text().await?;
+        assert_eq!(hits, "2");
+
+        drop(mytest);
+
+        Ok(())
+    }
+
+    /// Concurrent identical requests served an expired response because upstream fails reach it only once.
+    #[tokio::test]
+    async fn test_stale_if_error_is_shared() -> Result<(), Box<dyn std::error::Error>> {
+        let mytest = MyTest::new().await?;
+
+        // Call the proxy directly, without IC and the callback.
+        let toml_path = mytest.test.dir.path().join("config.toml");
+        let mut doc = read_to_string(&toml_path)?.parse::<DocumentMut>().context("Invalid TOML")?;
+        doc.remove("callback");
+        doc["cache"]["cache_timeout"] = value("1s");
+        doc["cache"]["stale_if_error"] = value("1m");
+        doc["cache"]["failure_ttl"] = value("10s");
+        write(&toml_path, doc.to_string()).context("Writing modified config.")?;
+        let _proxy = TemporaryChild::spawn(&mut Command::new(
+            mytest.test.workspace_dir.join("target").join("debug").join("join-proxy")
+        ).current_dir(mytest.test.dir.path()), Capture { stdout: None, stderr: None }).context("Running Joining Proxy")?;
+        sleep(Duration::from_millis(1000)).await; // Wait till the proxy starts.
+
+        // HTTP/1.1, as the proxy takes the upstream from the `Host` header.
+        let client = reqwest::Client::builder().http1_only().build()?;
+        let request = || client.get("https://local.vporton.name:8443/fails_later?delay_ms=500")
+            .header("host", "local.vporton.name:8081")
+            .send();
+        let response = request().await?;
+        assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "Miss");
+
+        sleep(Duration::from_millis(1500)).await; // Wait till the response expires.
+        let responses = join!(request(), request(), request(), request(), request());
+        let responses = [responses.0?, responses.1?, responses.2?, responses.3?, responses.4?];
+        for response in responses {
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "StaleIfError");
+            assert_eq!(response.text().await?, "fails later");
+        }
+
+        let hits = client.get("https://local.vporton.name:8081/hits").send().await?.text().await?;
+        assert_eq!(hits, "2");
+
+        drop(mytest);
+
+        Ok(())
+    }
+
+    /// An expired response with `ETag` is revalidated without downloading it again.
+    #[tokio::test]
+    async fn test_revalidation() -> Result<(), Box<dyn std::error::Error>> {
+        let mytest = MyTest::new().await?;
+
+        // Call the proxy directly, without IC and the callback.
+        let toml_path = mytest.test.dir.path().join("config.toml");
+        let mut doc = read_to_string(&toml_path)?.parse::<DocumentMut>().context("Invalid TOML")?;
+        doc.remove("callback");
+        doc["cache"]["cache_timeout"] = value("1s");
+        doc["cache"]["revalidation_period"] = value("1m");
+        write(&toml_path, doc.to_string()).context("Writing modified config.")?;
+        let _proxy = TemporaryChild::spawn(&mut Command::new(
+            mytest.test.workspace_dir.join("target").join("debug").join("join-proxy")
+        ).current_dir(mytest.test.dir.path()), Capture { stdout: None, stderr: None }).context("Running Joining Proxy")?;
+        sleep(Duration::from_millis(1000)).await; // Wait till the proxy starts.
+
+        // HTTP/1.1, as the proxy takes the upstream from the `Host` header.
+        let client = reqwest::Client::builder().http1_only().build()?;
+        let request = || client.get("https://local.vporton.name:8443/etag")
+            .header("host", "local.vporton.name:8081")
+            .send();
+        let response = request().await?;
+        assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "Miss");
+        assert_eq!(response.text().await?, "etag");
+
+        sleep(Duration::from_millis(1500)).await; // Wait till the response expires.
+        let response = request().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "Revalidated");
+        assert_eq!(response.text().await?, "etag");
+
+        let response = request().await?;
+        assert_eq!(response.headers().get("x-joinproxy-response").unwrap(), "Hit");
+
+        drop(mytest);
+
+        Ok(())
+    }
+}
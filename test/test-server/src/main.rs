@@ -1,4 +1,6 @@
 use std::{fs::File, io::BufReader};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 use std::vec::Vec;
 
 use clap::Parser;
@@ -7,7 +9,7 @@ use thiserror::Error;
 use anyhow::Context;
 use rustls::ServerConfig;
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use actix_web::{body::MessageBody, web::{self, Query}, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{body::MessageBody, web::{self, Data, Query}, App, HttpRequest, HttpResponse, HttpServer};
 use log::info;
 use anyhow::anyhow;
 use serde_derive::Deserialize;
@@ -24,7 +26,17 @@ struct TestServerArgs {
     arg: String,
 }
 
-async fn test_page(req: HttpRequest, args: Query<TestServerArgs>, body: web::Bytes) -> Result<HttpResponse, Box<(dyn std::error::Error + 'static)>> {
+#[derive(Deserialize)]
+struct SlowArgs {
+    delay_ms: u64,
+}
+
+/// Number of requests to test pages, to check how many requests the proxy sends upstream.
+#[derive(Default)]
+struct Hits(AtomicU64);
+
+async fn test_page(req: HttpRequest, args: Query<TestServerArgs>, body: web::Bytes, hits: Data<Hits>) -> Result<HttpResponse, Box<(dyn std::error::Error + 'static)>> {
+    hits.0.fetch_add(1, Ordering::Relaxed);
     let b = body.try_into_bytes().or_else(|_| Err(anyhow!("cannot read body")))?;
     let res = format!("path={}&arg={}&body={}", req.uri().path(), args.arg, String::from_utf8(Vec::from(&*b))?);
     info!("Test server serving: {}", req.uri().path_and_query().ok_or_else(|| anyhow!("error in path or query"))?);
@@ -33,6 +45,54 @@ async fn test_page(req: HttpRequest, args: Query<TestServerArgs>, body: web::Byt
         .body(res))
 }
 
+/// Responds after `delay_ms`, to make the proxy time out.
+async fn slow_page(args: Query<SlowArgs>, hits: Data<Hits>) -> HttpResponse {
+    hits.0.fetch_add(1, Ordering::Relaxed);
+    actix_web::rt::time::sleep(Duration::from_millis(args.delay_ms)).await;
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .body("slow")
+}
+
+/// Whether `/fails_later` has already responded.
+#[derive(Default)]
+struct Failing(AtomicBool);
+
+/// Responds successfully the first time, and with 503 after `delay_ms` since then.
+async fn fails_later_page(args: Query<SlowArgs>, hits: Data<Hits>, failing: Data<Failing>) -> HttpResponse {
+    hits.0.fetch_add(1, Ordering::Relaxed);
+    if !failing.0.swap(true, Ordering::Relaxed) {
+        return HttpResponse::Ok()
+            .content_type("text/plain")
+            .body("fails later");
+    }
+    actix_web::rt::time::sleep(Duration::from_millis(args.delay_ms)).await;
+    HttpResponse::ServiceUnavailable()
+        .content_type("text/plain")
+        .body("failed")
+}
+
+/// Responds with `ETag`, and with 304 if the client has the same version.
+async fn etag_page(req: HttpRequest, hits: Data<Hits>) -> HttpResponse {
+    hits.0.fetch_add(1, Ordering::Relaxed);
+    const ETAG: &str = "\"v1\"";
+    if req.headers().get("if-none-match").is_some_and(|v| v == ETAG) {
+        return HttpResponse::NotModified()
+            .insert_header(("etag", ETAG))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .insert_header(("etag", ETAG))
+        .body("etag")
+}
+
+async fn return_hits(hits: Data<Hits>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(hits.0.load(Ordering::Relaxed).to_string())
+}
+
 async fn return_headers(req: HttpRequest) -> Result<HttpResponse, Box<(dyn std::error::Error + 'static)>> {
     let mut res = "".to_string();
     for (k, v) in req.headers() {
@@ -70,9 +130,17 @@ async fn main() -> anyhow::Result<()> {
     let key = pkcs8_private_keys(key_file)
         .next().transpose()?.ok_or(anyhow!("No private key in the file."))?;
 
-    HttpServer::new(|| {
+    let hits = Data::new(Hits::default());
+    let failing = Data::new(Failing::default());
+    HttpServer::new(move || {
         App::new()
+            .app_data(hits.clone())
+            .app_data(failing.clone())
             .route("/headers", web::post().to(return_headers))
+            .route("/hits", web::get().to(return_hits))
+            .route("/slow", web::route().to(slow_page))
+            .route("/etag", web::get().to(etag_page))
+            .route("/fails_later", web::get().to(fails_later_page))
             .service(
                 // Define the general routes within a scope
                 web::scope("/{_:.*}")